@@ -0,0 +1,5648 @@
+// Without `std`, this only needs `alloc`, so it can be used where there's no
+// operating system. Reading from an `io::BufRead`, the `HashMap` of captures
+// and tracing to stderr all need `std`. The `ex08-no-std` crate next to this
+// one checks that it still builds that way.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Range;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead};
+
+use require_lifetimes::require_lifetimes;
+
+/// These are the characters with a special meaning in a pattern.
+/// To match one of them literally, put a `\` in front of it.
+const METACHARACTERS: &[char] = &['.', '(', ')', '|', '[', ']', '?', '*', '+', '{', '}', '^', '$', '\\'];
+
+/// This is how many options a group needs, if they're all plain text,
+/// before it's worth putting them in a `WordSet` instead of trying each one.
+const MIN_WORD_SET_LEN: usize = 8;
+
+/// These are the metacharacters which start a new token (or end a
+/// group or an alternative), so they also end any `RawText` before them.
+const TOKEN_STARTS: &[char] = &['.', '(', ')', '|', '[', '?', '*', '+', '{', '^', '$', '\\'];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MatcherToken <'a> {
+    /// This is just text without anything special. An escaped
+    /// metacharacter, like `\.`, is the `RawText` `.`. It's borrowed from the
+    /// pattern, unless `Matcher::normalize` had to join two texts together.
+    RawText(#[cfg_attr(feature = "serde", serde(borrow))] Cow<'a, str>),
+    /// This is when text could be any one of multiple
+    /// strings. It looks like `(one|two|three)`, where
+    /// `one`, `two` or `three` are the allowed strings.
+    /// Each of those is itself a list of tokens, so groups
+    /// can contain other groups, like `(a(b|c)|d)`.
+    OneOfText(#[cfg_attr(feature = "serde", serde(borrow))] Vec<Vec<MatcherToken<'a>>>),
+    /// This is a `OneOfText` with a name, so what it matched can be found
+    /// with `Matcher::captures`. It looks like `(?<name>one|two)`, and the
+    /// name can only have letters, digits and `_` in it.
+    NamedGroup {
+        name: &'a str,
+        #[cfg_attr(feature = "serde", serde(borrow))]
+        options: Vec<Vec<MatcherToken<'a>>>,
+    },
+    /// This is a `OneOfText` which ignores ASCII case, even if the rest of
+    /// the matcher doesn't. It looks like `(?i:one|two)`.
+    IgnoreCase(#[cfg_attr(feature = "serde", serde(borrow))] Vec<Vec<MatcherToken<'a>>>),
+    /// This is a `OneOfText` from `Matcher::new_lazy` whose options are all plain
+    /// text. It's the text between the parentheses, like `cat|dog` for
+    /// `(cat|dog)`, which is split at each `|` while matching. It's written the
+    /// same way as a `OneOfText`.
+    LazyGroup(&'a str),
+    /// This is when you're happy to accept any single character.
+    /// It looks like `.`
+    WildCard,
+    /// This is when you're happy to accept any single character
+    /// from a set. It looks like `[abc]`, or `[a-z]` for a range.
+    /// Starting with a `^`, like `[^abc]`, accepts any character *not*
+    /// in the set, so `[^]` accepts any character at all.
+    /// The shorthands `\d` (digits), `\w` (letters, digits and `_`) and
+    /// `\s` (whitespace) are also character classes. These only know
+    /// about ASCII, so `\d` doesn't accept something like `٣`.
+    /// Their uppercase forms `\D`, `\W` and `\S` accept anything else.
+    CharClass(CharSet),
+    /// This is when the token inside may appear zero or one times.
+    /// It looks like `?` after the token it applies to.
+    Optional(#[cfg_attr(feature = "serde", serde(borrow))] Box<MatcherToken<'a>>),
+    /// This is when the token inside may appear several times in a row,
+    /// at least `min` times and at most `max` times (if there is a `max`).
+    /// It looks like `*` (any number of times), `+` (at least once) or
+    /// `{min,max}` after the token it applies to.
+    /// It normally matches as many times as it can (it's `greedy`), but with
+    /// a `?` after it, like `*?`, it matches as few times as it can instead.
+    Repeat {
+        #[cfg_attr(feature = "serde", serde(borrow))]
+        inner: Box<MatcherToken<'a>>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+    /// This is a `OneOfText` where every option is plain text, and there
+    /// are enough of them that `Matcher::new` put them in a `WordSet`, so
+    /// they can be found without checking each one. It matches exactly like
+    /// the `OneOfText` would, trying the options in the order they're written.
+    OneOfWords(#[cfg_attr(feature = "serde", serde(borrow))] WordSet<'a>),
+    /// This only matches at the very start of the string, and doesn't
+    /// use up any of it. It looks like `^`.
+    StartAnchor,
+    /// This only matches at the very end of the string, and doesn't
+    /// use up any of it. It looks like `$`.
+    EndAnchor,
+    /// This only matches between a word character (what `\w` matches) and
+    /// something else, where the start and end of the string count as
+    /// something else. It doesn't use up any of the string. It looks like `\b`.
+    WordBoundary,
+}
+
+/// This is a set of characters, kept as sorted ranges which don't overlap,
+/// so that checking a character doesn't mean looking at every range.
+/// A negated set contains every character outside of its ranges.
+/// A set for `\p{...}` has a Unicode property instead of any ranges.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CharSet {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    property: Option<UnicodeProperty>,
+}
+
+/// This is a kind of character which `\p{...}` can match, by the short name of
+/// its Unicode general category. Without the Unicode tables, a `Letter` is what
+/// `char::is_alphabetic` accepts, which also has some marks and letter-like
+/// numbers in it, and `Punctuation` only knows about ASCII, Latin-1, the General
+/// Punctuation block and CJK punctuation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum UnicodeProperty {
+    /// This is `L`, or `Letter`.
+    Letter,
+    /// This is `N`, or `Number`.
+    Number,
+    /// This is `P`, or `Punctuation`.
+    Punctuation,
+}
+
+impl UnicodeProperty {
+    fn from_name(name: &str) -> Option<UnicodeProperty> {
+        match name {
+            "L" | "Letter" => Some(UnicodeProperty::Letter),
+            "N" | "Number" => Some(UnicodeProperty::Number),
+            "P" | "Punctuation" => Some(UnicodeProperty::Punctuation),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            UnicodeProperty::Letter => "L",
+            UnicodeProperty::Number => "N",
+            UnicodeProperty::Punctuation => "P",
+        }
+    }
+
+    fn contains(self, c: char) -> bool {
+        match self {
+            UnicodeProperty::Letter => c.is_alphabetic(),
+            UnicodeProperty::Number => c.is_numeric(),
+            UnicodeProperty::Punctuation => matches!(c,
+                '!'..='#' | '%'..='*' | ','..='/' | ':' | ';' | '?' | '@' | '['..=']' | '_' | '{' | '}'
+                | '¡' | '§' | '«' | '¶' | '·' | '»' | '¿'
+                | '\u{2010}'..='\u{2027}' | '\u{2030}'..='\u{2043}' | '\u{2045}'..='\u{2051}' | '\u{2053}'..='\u{205E}'
+                | '\u{3001}'..='\u{3003}' | '\u{3008}'..='\u{3011}' | '\u{3014}'..='\u{301F}'),
+        }
+    }
+}
+
+impl CharSet {
+    /// This builds a set from inclusive ranges in any order,
+    /// joining together any that overlap or touch.
+    fn from_ranges(mut ranges: Vec<(char, char)>) -> CharSet {
+        ranges.sort();
+        let mut merged: Vec<(char, char)> = vec![];
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start as u32 <= last.1 as u32 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        CharSet { ranges: merged, negated: false, property: None }
+    }
+
+    /// This is the set `\p{name}` stands for, if `name` is a property it knows.
+    fn property(name: &str) -> Option<CharSet> {
+        let property = UnicodeProperty::from_name(name)?;
+        Some(CharSet { ranges: vec![], negated: false, property: Some(property) })
+    }
+
+    /// This is the set a shorthand like `\d` stands for, if it is one.
+    fn shorthand(name: char) -> Option<CharSet> {
+        if name.is_ascii_uppercase() {
+            return Some(CharSet::shorthand(name.to_ascii_lowercase())?.negate());
+        }
+
+        let ranges = match name {
+            'd' => vec![('0', '9')],
+            'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+            's' => vec![(' ', ' '), ('\t', '\n'), ('\x0C', '\r')],
+            _ => return None,
+        };
+        Some(CharSet::from_ranges(ranges))
+    }
+
+    /// This flips the set, so it contains exactly what it didn't before.
+    fn negate(mut self) -> CharSet {
+        self.negated = !self.negated;
+        self
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.in_ranges(c) != self.negated
+    }
+
+    /// This is like `contains`, but also accepts `c` if its other ASCII case
+    /// is in the ranges, so `[a-z]` contains `Q` and `[^a]` doesn't contain `A`.
+    fn contains_ignoring_case(&self, c: char) -> bool {
+        let in_ranges = self.in_ranges(c)
+            || self.in_ranges(c.to_ascii_lowercase())
+            || self.in_ranges(c.to_ascii_uppercase());
+
+        in_ranges != self.negated
+    }
+
+    /// This is whether `c` is inside one of the ranges, or has the property,
+    /// ignoring `negated`.
+    fn in_ranges(&self, c: char) -> bool {
+        if let Some(property) = self.property {
+            return property.contains(c);
+        }
+        self.ranges
+            .binary_search_by(|&(start, end)| {
+                if end < c {
+                    core::cmp::Ordering::Less
+                } else if start > c {
+                    core::cmp::Ordering::Greater
+                } else {
+                    core::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+}
+
+impl fmt::Display for CharSet {
+    /// This writes the set so that parsing it gives the same set back.
+    /// Inside `[...]`, a `-` is written first so it isn't part of a range,
+    /// and a `^` is never written first so it doesn't negate the set.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(property) = self.property {
+            let escape = if self.negated { 'P' } else { 'p' };
+            return write!(f, "\\{escape}{{{}}}", property.name());
+        }
+        if let Some(name) = "dDwWsS".chars().find(|&name| CharSet::shorthand(name).as_ref() == Some(self)) {
+            return write!(f, "\\{name}");
+        }
+
+        let mut has_dash = false;
+        let mut ranges = vec![];
+        for &(start, end) in &self.ranges {
+            if start <= '-' && '-' <= end {
+                has_dash = true;
+                if start < '-' {
+                    ranges.push((start, ','));
+                }
+                if end > '-' {
+                    ranges.push(('.', end));
+                }
+            } else {
+                ranges.push((start, end));
+            }
+        }
+        if !has_dash && !self.negated && ranges.first().is_some_and(|&(start, _)| start == '^') {
+            ranges.rotate_left(1);
+        }
+
+        write!(f, "[")?;
+        if self.negated {
+            write!(f, "^")?;
+        }
+        if has_dash {
+            write!(f, "-")?;
+        }
+        for (start, end) in ranges {
+            if start == end {
+                write!(f, "{start}")?;
+            } else {
+                write!(f, "{start}-{end}")?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// This is a list of words, with a trie of their bytes so that finding which
+/// of them a string starts with only means walking along the string once.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WordSet<'a> {
+    /// These are the words, in the order they were written.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    words: Vec<&'a str>,
+    /// This is the trie, starting from the root at `nodes[0]`. A `Program`
+    /// keeps its own, and lends it out to match with.
+    nodes: Cow<'a, [TrieNode]>,
+}
+
+/// This is one node of a `WordSet`'s trie, for the bytes that lead to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct TrieNode {
+    /// These are the next byte and the node it leads to, sorted by byte.
+    children: Vec<(u8, usize)>,
+    /// This is the first word which ends here, if any do.
+    word: Option<usize>,
+}
+
+impl<'a> WordSet<'a> {
+    fn new(words: Vec<&'a str>) -> WordSet<'a> {
+        let mut nodes = vec![TrieNode::default()];
+        for (index, word) in words.iter().enumerate() {
+            let mut node = 0;
+            for &byte in word.as_bytes() {
+                node = match nodes[node].children.binary_search_by_key(&byte, |&(b, _)| b) {
+                    Ok(child) => nodes[node].children[child].1,
+                    Err(child) => {
+                        nodes.push(TrieNode::default());
+                        let new_node = nodes.len() - 1;
+                        nodes[node].children.insert(child, (byte, new_node));
+                        new_node
+                    }
+                };
+            }
+            nodes[node].word.get_or_insert(index);
+        }
+        WordSet { words, nodes: Cow::Owned(nodes) }
+    }
+
+    /// This returns the index and length of every word `text` starts with,
+    /// in the order the words were written.
+    fn prefixes_of(&self, text: &[u8]) -> Vec<(usize, usize)> {
+        let mut found = vec![];
+        let mut node = 0;
+        let mut bytes = text.iter().copied().enumerate();
+        loop {
+            if let Some(index) = self.nodes[node].word {
+                found.push((index, self.words[index].len()));
+            }
+            let Some((_, byte)) = bytes.next() else { break };
+            match self.nodes[node].children.binary_search_by_key(&byte, |&(b, _)| b) {
+                Ok(child) => node = self.nodes[node].children[child].1,
+                Err(_) => break,
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+}
+
+impl fmt::Display for MatcherToken<'_> {
+    /// This writes the token in the same syntax `Matcher::new` parses.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatcherToken::RawText(text) => {
+                for c in text.chars() {
+                    if METACHARACTERS.contains(&c) {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{c}")?;
+                }
+                Ok(())
+            }
+            MatcherToken::OneOfText(options) => {
+                write!(f, "(")?;
+                MatcherToken::fmt_options(options, f)?;
+                write!(f, ")")
+            }
+            MatcherToken::NamedGroup { name, options } => {
+                write!(f, "(?<{name}>")?;
+                MatcherToken::fmt_options(options, f)?;
+                write!(f, ")")
+            }
+            MatcherToken::IgnoreCase(options) => {
+                write!(f, "(?i:")?;
+                MatcherToken::fmt_options(options, f)?;
+                write!(f, ")")
+            }
+            MatcherToken::LazyGroup(inside) => write!(f, "({inside})"),
+            MatcherToken::OneOfWords(set) => {
+                write!(f, "(")?;
+                for (i, word) in set.words.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "|")?;
+                    }
+                    write!(f, "{}", MatcherToken::RawText(Cow::Borrowed(word)))?;
+                }
+                write!(f, ")")
+            }
+            MatcherToken::WildCard => write!(f, "."),
+            MatcherToken::CharClass(set) => write!(f, "{set}"),
+            MatcherToken::Optional(inner) => {
+                inner.fmt_quantified(f)?;
+                write!(f, "?")
+            }
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                inner.fmt_quantified(f)?;
+                match (min, max) {
+                    (0, None) => write!(f, "*")?,
+                    (1, None) => write!(f, "+")?,
+                    (min, None) => write!(f, "{{{min},}}")?,
+                    (min, Some(max)) if min == max => write!(f, "{{{min}}}")?,
+                    (min, Some(max)) => write!(f, "{{{min},{max}}}")?,
+                }
+                if !greedy {
+                    write!(f, "?")?;
+                }
+                Ok(())
+            }
+            MatcherToken::StartAnchor => write!(f, "^"),
+            MatcherToken::EndAnchor => write!(f, "$"),
+            MatcherToken::WordBoundary => write!(f, "\\b"),
+        }
+    }
+}
+
+impl MatcherToken<'_> {
+    /// This is whether the token only checks where it is, without ever using up
+    /// any of the string, so writing it twice in a row is the same as once.
+    fn is_zero_width(&self) -> bool {
+        matches!(self, MatcherToken::StartAnchor | MatcherToken::EndAnchor | MatcherToken::WordBoundary)
+    }
+
+    /// This is whether the token can match without using up any of the string.
+    fn matches_empty(&self) -> bool {
+        let option_matches_empty = |option: &Vec<MatcherToken>| option.iter().all(MatcherToken::matches_empty);
+
+        match self {
+            MatcherToken::RawText(text) => text.is_empty(),
+            MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => {
+                options.iter().any(option_matches_empty)
+            }
+            MatcherToken::LazyGroup(inside) => inside.split('|').any(str::is_empty),
+            MatcherToken::OneOfWords(set) => set.words.contains(&""),
+            MatcherToken::WildCard | MatcherToken::CharClass(_) => false,
+            MatcherToken::Optional(_) => true,
+            MatcherToken::Repeat { inner, min, .. } => *min == 0 || inner.matches_empty(),
+            MatcherToken::StartAnchor | MatcherToken::EndAnchor | MatcherToken::WordBoundary => true,
+        }
+    }
+
+    /// This is the fewest and the most bytes the token can match, where the most
+    /// is `None` if there's no limit. A character is up to 4 bytes, but with
+    /// `dot_matches_grapheme` a `.` can match any number of them.
+    fn length_bounds(&self, options: &MatchOptions) -> (usize, Option<usize>) {
+        let alternatives = |bounds: &mut dyn Iterator<Item = (usize, Option<usize>)>| {
+            bounds
+                .reduce(|(min, max), (other_min, other_max)| (min.min(other_min), max.zip(other_max).map(|(a, b)| a.max(b))))
+                .unwrap_or((0, Some(0)))
+        };
+
+        match self {
+            MatcherToken::RawText(text) => (text.len(), Some(text.len())),
+            MatcherToken::OneOfText(group) | MatcherToken::NamedGroup { options: group, .. } | MatcherToken::IgnoreCase(group) => {
+                alternatives(&mut group.iter().map(|option| MatcherToken::sequence_length_bounds(option, options)))
+            }
+            MatcherToken::LazyGroup(inside) => alternatives(&mut inside.split('|').map(|option| (option.len(), Some(option.len())))),
+            MatcherToken::OneOfWords(set) => alternatives(&mut set.words.iter().map(|word| (word.len(), Some(word.len())))),
+            MatcherToken::WildCard if options.dot_matches_grapheme => (1, None),
+            MatcherToken::WildCard => (1, Some(4)),
+            MatcherToken::CharClass(set) if set.negated || set.property.is_some() => (1, Some(4)),
+            // The ranges are sorted, so the first one starts with the shortest character,
+            // and the last one ends with the longest.
+            MatcherToken::CharClass(set) => (
+                set.ranges.first().map_or(1, |&(start, _)| start.len_utf8()),
+                Some(set.ranges.last().map_or(1, |&(_, end)| end.len_utf8())),
+            ),
+            MatcherToken::Optional(inner) => (0, inner.length_bounds(options).1),
+            MatcherToken::Repeat { inner, min, max, .. } => {
+                let (inner_min, inner_max) = inner.length_bounds(options);
+                let most = match (max, inner_max) {
+                    (_, Some(0)) | (Some(0), _) => Some(0),
+                    (Some(max), Some(inner_max)) => max.checked_mul(inner_max),
+                    _ => None,
+                };
+                (inner_min.saturating_mul(*min), most)
+            }
+            MatcherToken::StartAnchor | MatcherToken::EndAnchor | MatcherToken::WordBoundary => (0, Some(0)),
+        }
+    }
+
+    /// This is how many characters the token always matches, or `None` if it can
+    /// match different numbers of them. With `dot_matches_grapheme`, a `.` can.
+    fn fixed_width(&self, options: &MatchOptions) -> Option<usize> {
+        fn same_width(mut widths: impl Iterator<Item = Option<usize>>) -> Option<usize> {
+            let first = widths.next()??;
+            widths.all(|width| width == Some(first)).then_some(first)
+        }
+
+        match self {
+            MatcherToken::RawText(text) => Some(text.chars().count()),
+            MatcherToken::OneOfText(group) | MatcherToken::NamedGroup { options: group, .. } | MatcherToken::IgnoreCase(group) => {
+                same_width(group.iter().map(|option| MatcherToken::sequence_fixed_width(option, options)))
+            }
+            MatcherToken::LazyGroup(inside) => same_width(inside.split('|').map(|option| Some(option.chars().count()))),
+            MatcherToken::OneOfWords(set) => same_width(set.words.iter().map(|word| Some(word.chars().count()))),
+            MatcherToken::WildCard if options.dot_matches_grapheme => None,
+            MatcherToken::WildCard | MatcherToken::CharClass(_) => Some(1),
+            MatcherToken::Optional(inner) => inner.fixed_width(options).filter(|&width| width == 0),
+            MatcherToken::Repeat { inner, min, max, .. } => match inner.fixed_width(options)? {
+                0 => Some(0),
+                width if *max == Some(*min) => width.checked_mul(*min),
+                _ => None,
+            },
+            MatcherToken::StartAnchor | MatcherToken::EndAnchor | MatcherToken::WordBoundary => Some(0),
+        }
+    }
+
+    /// This is the token for `Matcher::debug_tokens`: its variant, and what's in it.
+    fn debug_line(&self) -> String {
+        let options = |options: &[Vec<MatcherToken>]| {
+            let written: Vec<String> = options.iter().map(|option| option.iter().map(ToString::to_string).collect()).collect();
+            written.join(",")
+        };
+
+        match self {
+            MatcherToken::RawText(text) => format!("RawText({text:?})"),
+            MatcherToken::OneOfText(group) => format!("OneOfText([{}])", options(group)),
+            MatcherToken::NamedGroup { name, options: group } => format!("NamedGroup({name}, [{}])", options(group)),
+            MatcherToken::IgnoreCase(group) => format!("IgnoreCase([{}])", options(group)),
+            MatcherToken::LazyGroup(inside) => format!("LazyGroup({inside:?})"),
+            MatcherToken::OneOfWords(set) => format!("OneOfWords([{}])", set.words.join(",")),
+            MatcherToken::WildCard => "WildCard".to_string(),
+            MatcherToken::CharClass(set) => format!("CharClass({set})"),
+            MatcherToken::Optional(inner) => format!("Optional({inner})"),
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                let max = max.map_or(String::new(), |max| format!("={max}"));
+                let lazy = if *greedy { "" } else { ", lazy" };
+                format!("Repeat({inner}, {min}..{max}{lazy})")
+            }
+            MatcherToken::StartAnchor => "StartAnchor".to_string(),
+            MatcherToken::EndAnchor => "EndAnchor".to_string(),
+            MatcherToken::WordBoundary => "WordBoundary".to_string(),
+        }
+    }
+
+    /// This is `fixed_width` for tokens which match one after another.
+    fn sequence_fixed_width(tokens: &[MatcherToken], options: &MatchOptions) -> Option<usize> {
+        tokens.iter().try_fold(0usize, |width, token| width.checked_add(token.fixed_width(options)?))
+    }
+
+    /// This is `length_bounds` for tokens which match one after another.
+    fn sequence_length_bounds(tokens: &[MatcherToken], options: &MatchOptions) -> (usize, Option<usize>) {
+        tokens.iter().fold((0, Some(0)), |(min, max), token| {
+            let (token_min, token_max) = token.length_bounds(options);
+            (min.saturating_add(token_min), max.zip(token_max).and_then(|(a, b)| a.checked_add(b)))
+        })
+    }
+
+    /// This writes a token which a quantifier applies to. A quantifier after
+    /// text only applies to its last character, so longer text is put in a group.
+    fn fmt_quantified(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatcherToken::RawText(text) if text.chars().count() > 1 => write!(f, "({self})"),
+            _ => write!(f, "{self}"),
+        }
+    }
+
+    /// This writes the options of a group, with a `|` between each of them.
+    fn fmt_options(options: &[Vec<MatcherToken>], f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, option) in options.iter().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+            for token in option {
+                write!(f, "{token}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// This is an iterator over the matches in a haystack, from `Matcher::matches_iter`.
+/// It yields the start and end of each match as byte offsets.
+pub struct Matches<'m, 'a, 'h> {
+    matcher: &'m mut Matcher<'a>,
+    haystack: &'h str,
+    /// This is where to look for the next match, or `None` once
+    /// the end of the haystack has been searched.
+    from: Option<usize>,
+}
+
+impl Iterator for Matches<'_, '_, '_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let found = self.matcher.find_from(self.haystack, self.from?);
+        self.from = found.and_then(|found| Matcher::resume_after(self.haystack, found));
+        found
+    }
+}
+
+/// This is one piece of a replacement, which `Matcher::template` splits it into.
+#[derive(Debug, PartialEq, Eq)]
+enum Replacement<'r> {
+    /// This is text to copy in as it is.
+    Text(&'r str),
+    /// This is `$0`, for the whole match.
+    Match,
+    /// This is `$name`, for what the named group matched.
+    Group(&'r str),
+}
+
+/// This is everything `Matcher::evaluate` found out about matching a string.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MatchResult<'a, 'b, 'c> {
+    /// This is what `match_string` returns: each token, and the part of the string it matched.
+    pub tokens: Vec<(&'b MatcherToken<'a>, &'c str)>,
+    /// This is how many bytes at the start of the string the tokens matched.
+    pub consumed: usize,
+    /// This is whether all of the tokens matched.
+    pub complete: bool,
+}
+
+/// This is the option of a group which matched, from `Matcher::match_with_alternatives`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Alternative<'a, 'b> {
+    /// This is the index of the option, like `match_with_choices` gives.
+    pub index: usize,
+    /// This is the option's own tokens, so it can be looked at like a whole pattern.
+    pub tokens: &'b [MatcherToken<'a>],
+}
+
+/// This is the result of trying to match some tokens against a string.
+struct Attempt<'a, 'b> {
+    /// This is the range of the string each token matched. If the tokens
+    /// couldn't all match, it's the attempt which got the furthest.
+    answer: Vec<(&'b MatcherToken<'a>, Range<usize>)>,
+    /// This is the byte offset in the string where `answer` stopped.
+    end: usize,
+    /// This is whether all of the tokens matched.
+    complete: bool,
+    /// This is the most tokens that any attempt matched.
+    progress: usize,
+    /// This is the furthest byte offset that any attempt got to.
+    furthest: usize,
+}
+
+impl<'a, 'b> Attempt<'a, 'b> {
+    /// This is the text each token matched, leaving out the tokens (like anchors)
+    /// which matched without using up any of it.
+    fn substrings<'c>(self, string: &'c str) -> Vec<(&'b MatcherToken<'a>, &'c str)> {
+        self.answer
+            .into_iter()
+            .filter(|(_, range)| !range.is_empty())
+            .map(|(token, range)| (token, &string[range]))
+            .collect()
+    }
+}
+
+/// This is the tokens that have matched so far in an attempt, and the range of the
+/// string each one matched. `consumed` is how many of them used up some text, which
+/// is how far along the attempt has got.
+#[derive(Clone, Default)]
+struct Path<'a, 'b> {
+    steps: Vec<(&'b MatcherToken<'a>, Range<usize>)>,
+    consumed: usize,
+}
+
+impl<'a, 'b> Path<'a, 'b> {
+    fn push(&mut self, token: &'b MatcherToken<'a>, range: Range<usize>) {
+        if !range.is_empty() {
+            self.consumed += 1;
+        }
+        self.steps.push((token, range));
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, range)) = self.steps.pop() {
+            if !range.is_empty() {
+                self.consumed -= 1;
+            }
+        }
+    }
+}
+
+/// These change how a `Matcher` compares its tokens against a string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct MatchOptions {
+    /// This makes raw text, groups and character classes match ignoring ASCII
+    /// case, so `abc` matches `ABC`. Other letters are compared exactly, so
+    /// `é` doesn't match `É`. A `.` already matches any case.
+    pub case_insensitive: bool,
+    /// This makes a `.` match a `\n` as well. By default it doesn't, so that
+    /// `.*` in text with several lines stays on one line.
+    pub dot_matches_newline: bool,
+    /// This sorts the options of every group longest first when the pattern is
+    /// parsed, so `(a|ab)` matches all of `ab` instead of stopping after `a`. An
+    /// option's length is how long it's written in the pattern. The tradeoff is
+    /// that the options aren't tried in the order they were written any more,
+    /// so the index `match_with_choices` gives is for the sorted options, and
+    /// the matcher is written out with them sorted.
+    pub longest_alternative_first: bool,
+    /// This makes a `.` match a whole character as it's shown, like an emoji with
+    /// a skin tone, which can be several `char`s. By default a `.` matches one.
+    /// See `grapheme_len` for how close that is without the `unicode-segmentation`
+    /// feature.
+    pub dot_matches_grapheme: bool,
+    /// This is which characters a `.` matches, if not all of them. A `\n` still
+    /// needs `dot_matches_newline` as well, and for a grapheme it's the first
+    /// `char` of it which is checked.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub wildcard: Option<Wildcard>,
+    /// This is told about each token as it's tried, for debugging a pattern.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub trace: Option<Trace>,
+    /// This makes `find`, and everything which searches like it, skip matches
+    /// which don't start and end at a word boundary, as if the pattern was
+    /// wrapped in `\b`, so `cat` is found in `the cat` but not in `category`.
+    pub whole_words: bool,
+    /// This makes a group which didn't match count towards `tokens_matched` and
+    /// `furthest_byte` if the string starts with part of the text one of its options
+    /// starts with, like `ca` for `(cat|car)`, which is what someone who's still
+    /// typing might have. It still isn't part of what `match_string` returns. Only
+    /// groups which aren't inside another token are counted like this.
+    pub partial_alternatives: bool,
+}
+
+/// This is a function which is called each time a token is tried while matching,
+/// with the token, the byte offset it was tried at, and what it matched, or
+/// `None` if it couldn't match there. A token which could match in several ways
+/// is reported once for each way that's tried, as the search backtracks.
+/// `trace_to_stderr` is one which prints each of them. Like a `Wildcard`, two
+/// are only equal if they're the same function.
+#[derive(Debug, Clone, Copy)]
+pub struct Trace(pub fn(&MatcherToken, usize, Option<&str>));
+
+impl PartialEq for Trace {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 as usize == other.0 as usize
+    }
+}
+
+impl Eq for Trace {}
+
+impl Hash for Trace {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state);
+    }
+}
+
+/// This is a `Trace` function which prints what each token matched to stderr.
+#[cfg(feature = "std")]
+pub fn trace_to_stderr(token: &MatcherToken, position: usize, matched: Option<&str>) {
+    match matched {
+        Some(matched) => eprintln!("`{token}` at byte {position} matched {matched:?}"),
+        None => eprintln!("`{token}` at byte {position} didn't match"),
+    }
+}
+
+impl MatchOptions {
+    /// This is whether a search should keep the match from `start` to `end`.
+    fn keeps_match(&self, haystack: &str, (start, end): (usize, usize)) -> bool {
+        !self.whole_words || (is_word_boundary(haystack, start) && is_word_boundary(haystack, end))
+    }
+}
+
+/// This is the function deciding which characters a `.` matches. Two are only
+/// equal if they're the same function at the same address, which is as close
+/// as functions can be compared, so a copy of a matcher still equals it.
+#[derive(Debug, Clone, Copy)]
+pub struct Wildcard(pub fn(char) -> bool);
+
+impl PartialEq for Wildcard {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 as usize == other.0 as usize
+    }
+}
+
+impl Eq for Wildcard {}
+
+impl Hash for Wildcard {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state);
+    }
+}
+
+/// With the `serde` feature, a `Matcher` can be serialized, and deserialized by
+/// borrowing its text straight out of the serialized data, so that data has to
+/// live as long as the `Matcher`. Plain text which had to be unescaped (like a
+/// `\\` in JSON) is copied, but a group's name or one of a big group of words
+/// can't be, so use `MatcherOwned` for those.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Matcher <'a> {
+    /// This is the actual text of the matcher. It's borrowed, like the
+    /// tokens, unless the matcher was made by joining others with `concat`.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    text: Cow<'a, str>,
+    /// This is a vector of the tokens inside the expression.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    tokens: Vec<MatcherToken<'a>>,
+    /// This is how the tokens are compared against a string.
+    #[cfg_attr(feature = "serde", serde(default))]
+    options: MatchOptions,
+    /// This keeps track of how far this matcher has got matching strings.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    progress: Progress,
+}
+
+/// This is how far a matcher has got matching strings. It's kept apart from the
+/// tokens, so it can be updated while what matched still borrows them.
+#[derive(Debug, Clone, Copy, Default)]
+struct Progress {
+    /// This is the most tokens that the matcher has matched.
+    most_tokens_matched: usize,
+    /// This is the furthest byte offset into a string that the matcher has
+    /// got to, which is more precise than counting tokens.
+    most_bytes_matched: usize,
+}
+
+impl Progress {
+    /// This updates `most_tokens_matched` and `most_bytes_matched`,
+    /// if `tokens` or `bytes` are more than them.
+    fn record(&mut self, tokens: usize, bytes: usize) {
+        self.most_tokens_matched = self.most_tokens_matched.max(tokens);
+        self.most_bytes_matched = self.most_bytes_matched.max(bytes);
+    }
+}
+
+/// Matchers are equal if they have the same text, tokens and options. How far they
+/// have got matching strings is left out, so it doesn't change a matcher's hash
+/// when it's used as a key.
+impl PartialEq for Matcher<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text && self.tokens == other.tokens && self.options == other.options
+    }
+}
+
+impl Eq for Matcher<'_> {}
+
+impl Hash for Matcher<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text.hash(state);
+        self.tokens.hash(state);
+        self.options.hash(state);
+    }
+}
+
+impl fmt::Display for Matcher<'_> {
+    /// This writes out the pattern from the tokens, rather than just
+    /// writing `text`, so it stays right if the tokens are changed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            write!(f, "{token}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Matcher<'a> {
+    /// This should take a string reference, and return
+    /// an `Matcher` which has parsed that reference, or
+    /// an error saying what's wrong with it.
+    /// A `|` outside of any group splits the whole pattern into alternatives,
+    /// so `ab(c|d)|ef` is the single group `(ab(c|d)|ef)`.
+    /// Each group is made into a list of options, each a list of tokens, so
+    /// it allocates even if it's small. `new_lazy` doesn't, for plain text.
+    #[require_lifetimes]
+    pub fn new(text: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::with_max_alternatives(text, usize::MAX)
+    }
+
+    /// This is like `new`, but a group with more than `max_alternatives` options
+    /// is an error, so a pattern from somewhere untrusted can't make a huge one.
+    /// A `|` outside of any group counts as making a group, too.
+    #[require_lifetimes]
+    pub fn with_max_alternatives(text: &'a str, max_alternatives: usize) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::parse(text, max_alternatives, false)
+    }
+
+    /// This is like `new`, but a group whose options are all plain text, like
+    /// `(cat|dog)`, is kept as its text and split into its options while
+    /// matching. It's never made into tokens, so it doesn't allocate, and a big
+    /// pattern of them is quicker to make. Other groups are parsed like `new`
+    /// parses them. It's an error just when it would be for `new`.
+    /// `MatcherBuilder::lazy_groups` does this with a limit on alternatives, too.
+    #[require_lifetimes]
+    pub fn new_lazy(text: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::parse(text, usize::MAX, true)
+    }
+
+    /// This is what `with_max_alternatives` and `new_lazy` both do.
+    #[require_lifetimes]
+    fn parse(text: &'a str, max_alternatives: usize, lazy_groups: bool) -> Result<Matcher<'a>, MatcherParseError> {
+        let (tokens, leftover) = Self::parse_pattern(text, max_alternatives, lazy_groups)?;
+        if !leftover.is_empty() {
+            // The only thing that stops the parser early is a `)` with no `(`.
+            return Err(MatcherParseError::UnbalancedParen { at: text.len() - leftover.len() });
+        }
+
+        Ok(Matcher { text: Cow::Borrowed(text), tokens, options: MatchOptions::default(), progress: Progress::default() })
+    }
+
+    /// This parses the pattern like `new`, but also gives advice about parts of
+    /// it which are probably mistakes, even though they parse, like `.*.*` or
+    /// `(a|a)`. If the pattern can't be parsed, the advice is just the error.
+    #[require_lifetimes]
+    pub fn analyze(text: &'a str) -> (Option<Matcher<'a>>, Vec<String>) {
+        let matcher = match Self::new(text) {
+            Ok(matcher) => matcher,
+            Err(error) => return (None, vec![error.to_string()]),
+        };
+
+        let mut advice = vec![];
+        Self::advise(&matcher.tokens, &mut advice);
+        if matcher.matches_empty() {
+            advice.push("the pattern can match nothing, so `find_all` and `split` can find empty matches".to_string());
+        }
+        (Some(matcher), advice)
+    }
+
+    /// This adds advice about the tokens, and any tokens inside them, to `advice`.
+    fn advise(tokens: &[MatcherToken], advice: &mut Vec<String>) {
+        for pair in tokens.windows(2) {
+            let repeats_forever = matches!(pair[0], MatcherToken::Repeat { max: None, .. });
+            if pair[0] == pair[1] && (repeats_forever || pair[0].is_zero_width()) {
+                advice.push(format!("`{}{}` matches the same as `{}`", pair[0], pair[1], pair[0]));
+            }
+        }
+
+        for token in tokens {
+            match token {
+                MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => {
+                    for (index, option) in options.iter().enumerate() {
+                        if options[..index].contains(option) {
+                            let written: String = option.iter().map(ToString::to_string).collect();
+                            advice.push(format!("the option `{written}` is in `{token}` more than once"));
+                        }
+                        Self::advise(option, advice);
+                    }
+                }
+                MatcherToken::Optional(inner) | MatcherToken::Repeat { inner, .. } => {
+                    if let MatcherToken::Repeat { min: 1, max: Some(1), .. } = token {
+                        advice.push(format!("`{token}` matches the same as `{inner}`"));
+                    } else if inner.matches_empty() {
+                        advice.push(format!("`{token}` repeats `{inner}`, which can already match nothing"));
+                    }
+                    Self::advise(core::slice::from_ref(&**inner), advice);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// This is like `new`, but the `Matcher` ignores ASCII case when it
+    /// matches, so `(cat|dog)` matches `CAT`.
+    #[require_lifetimes]
+    pub fn new_case_insensitive(text: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::with_options(text, MatchOptions { case_insensitive: true, ..MatchOptions::default() })
+    }
+
+    /// This is like `new`, but the `Matcher` matches with the given options.
+    #[require_lifetimes]
+    pub fn with_options(text: &'a str, options: MatchOptions) -> Result<Matcher<'a>, MatcherParseError> {
+        let mut matcher = Self::new(text)?;
+        matcher.set_options(options);
+        Ok(matcher)
+    }
+
+    /// This changes the options of a matcher which was just parsed.
+    pub fn set_options(&mut self, options: MatchOptions) {
+        self.options = options;
+        if options.longest_alternative_first {
+            Self::sort_alternatives(&mut self.tokens);
+        }
+    }
+
+    /// This sorts the options of every group in the tokens longest first, for
+    /// `MatchOptions::longest_alternative_first`. Options of the same length
+    /// stay in the order they were in.
+    fn sort_alternatives(tokens: &mut [MatcherToken<'a>]) {
+        let written_len = |option: &Vec<MatcherToken>| option.iter().map(|token| token.to_string().len()).sum::<usize>();
+
+        for token in tokens {
+            match token {
+                MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => {
+                    for option in options.iter_mut() {
+                        Self::sort_alternatives(option);
+                    }
+                    options.sort_by_key(|option| core::cmp::Reverse(written_len(option)));
+                }
+                MatcherToken::OneOfWords(set) => {
+                    let mut words = set.words.clone();
+                    words.sort_by_key(|word| core::cmp::Reverse(word.len()));
+                    *set = WordSet::new(words);
+                }
+                // The text can't be put in another order, so it's made into tokens.
+                MatcherToken::LazyGroup(inside) => {
+                    let mut options: Vec<Vec<MatcherToken>> =
+                        inside.split('|').map(|option| vec![MatcherToken::RawText(Cow::Borrowed(option))]).collect();
+                    options.sort_by_key(|option| core::cmp::Reverse(written_len(option)));
+                    *token = MatcherToken::OneOfText(options);
+                }
+                MatcherToken::Optional(inner) | MatcherToken::Repeat { inner, .. } => {
+                    Self::sort_alternatives(core::slice::from_mut(&mut **inner));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// This is like `new`, but a `.` in the pattern only matches characters which
+    /// `wildcard` returns `true` for, like `char::is_alphabetic`.
+    #[require_lifetimes]
+    pub fn with_wildcard(text: &'a str, wildcard: fn(char) -> bool) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::with_options(text, MatchOptions { wildcard: Some(Wildcard(wildcard)), ..MatchOptions::default() })
+    }
+
+    /// This makes a matcher which matches exactly `text`, even if it has `.`, `(`
+    /// or any other metacharacter in it. Its own text is `text` with those
+    /// escaped, so `a.b` becomes `a\.b`.
+    #[require_lifetimes]
+    pub fn new_literal(text: &'a str) -> Matcher<'a> {
+        let tokens = if text.is_empty() { vec![] } else { vec![MatcherToken::RawText(Cow::Borrowed(text))] };
+        Self::from_tokens(tokens)
+    }
+
+    /// This copies `text` into `arena` and parses the copy, so the matcher
+    /// borrows from the arena rather than from `text`. Patterns from many places can
+    /// be put in one arena, and then all their matchers have the same lifetime.
+    pub fn new_in(text: &str, arena: &'a Arena) -> Result<Matcher<'a>, MatcherParseError> {
+        Self::new(arena.alloc(text))
+    }
+
+    /// This makes a matcher from a glob, like `*.txt`, where `*` matches any
+    /// number of characters, `?` matches any one character, and everything else is
+    /// matched as it is. Like a `.`, neither of them matches `\n`. The literal
+    /// tokens borrow from `pattern`, but the matcher's text is the pattern it's the
+    /// same as, like `.*\.txt`, so it's owned. Every glob is valid for now, but this
+    /// returns a `Result` like the other constructors do.
+    #[require_lifetimes]
+    pub fn new_glob(pattern: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        let mut tokens = vec![];
+        let mut literal_start = 0;
+        for (position, c) in pattern.char_indices() {
+            let token = match c {
+                '*' => MatcherToken::Repeat { inner: Box::new(MatcherToken::WildCard), min: 0, max: None, greedy: true },
+                '?' => MatcherToken::WildCard,
+                _ => continue,
+            };
+            if literal_start < position {
+                tokens.push(MatcherToken::RawText(Cow::Borrowed(&pattern[literal_start..position])));
+            }
+            tokens.push(token);
+            literal_start = position + 1;
+        }
+        if literal_start < pattern.len() {
+            tokens.push(MatcherToken::RawText(Cow::Borrowed(&pattern[literal_start..])));
+        }
+        Ok(Self::from_tokens(tokens))
+    }
+
+    /// This makes a matcher from tokens which were built in code, rather than
+    /// parsed, so a pattern can be put together without writing it out and
+    /// parsing it again. The tokens can borrow their text from anywhere. The
+    /// matcher's own text is written out from the tokens, so it parses back into
+    /// a matcher which matches the same strings.
+    #[require_lifetimes]
+    pub fn from_tokens(tokens: Vec<MatcherToken<'a>>) -> Matcher<'a> {
+        let mut matcher = Matcher { text: Cow::Borrowed(""), tokens, options: MatchOptions::default(), progress: Progress::default() };
+        matcher.text = Cow::Owned(matcher.to_string());
+        matcher
+    }
+
+    /// This parses each of the patterns into a `MultiMatcher`, which finds which of
+    /// them matches. The first pattern which can't be parsed gives the error.
+    pub fn new_multi(patterns: &[&'a str]) -> Result<MultiMatcher<'a>, MatcherParseError> {
+        let matchers = patterns.iter().map(|pattern| Matcher::new(pattern)).collect::<Result<_, _>>()?;
+        Ok(MultiMatcher { matchers })
+    }
+
+    /// This compiles the matcher into a `Program`, which owns everything it needs
+    /// to match with, so it can be kept after the pattern's text is gone.
+    pub fn compile(&self) -> Program {
+        Program { tokens: self.tokens.iter().map(ProgramToken::compile).collect(), options: self.options }
+    }
+
+    /// This makes a matcher which matches what `self` matches, followed by what
+    /// `other` matches. The tokens still borrow from both texts, but the text is
+    /// written out again from the tokens, since just putting the two texts next
+    /// to each other would be wrong if one of them was like `a|b`.
+    #[require_lifetimes]
+    pub fn concat(mut self, other: Matcher<'a>) -> Matcher<'a> {
+        self.tokens.extend(other.tokens);
+        self.text = Cow::Owned(self.to_string());
+        self.reset_progress();
+        self
+    }
+
+    /// This rewrites the tokens into a simpler form which matches the same strings,
+    /// so matchers written in different ways are more likely to compare equal. It
+    /// takes groups with only one option out of their group, turns `{1}` into the
+    /// token itself and `{0,1}` into `?`, drops `{0}`, and drops anchors which are
+    /// repeated, like `^^`. `RawText` tokens next to each other, like the `a` and
+    /// `b` from `(a)b`, are joined into one, which copies their text. The text is
+    /// written out again from the new tokens.
+    pub fn normalize(&mut self) {
+        self.tokens = Self::normalize_sequence(core::mem::take(&mut self.tokens));
+        let text = self.to_string();
+        if self.text != text {
+            self.text = Cow::Owned(text);
+        }
+    }
+
+    /// This normalizes one list of tokens, which a group with one option can be
+    /// spliced into.
+    fn normalize_sequence(tokens: Vec<MatcherToken<'a>>) -> Vec<MatcherToken<'a>> {
+        let mut normalized: Vec<MatcherToken<'a>> = vec![];
+        for token in tokens {
+            match Self::normalize_token(token) {
+                MatcherToken::OneOfText(mut options) if options.len() == 1 => {
+                    for token in options.pop().unwrap_or_default() {
+                        Self::push_normalized(&mut normalized, token);
+                    }
+                }
+                MatcherToken::Repeat { max: Some(0), .. } => {}
+                token => Self::push_normalized(&mut normalized, token),
+            }
+        }
+        normalized
+    }
+
+    /// This adds a normalized token to the end of `normalized`, joining it onto
+    /// the text before it, or leaving it out if it's an anchor repeated.
+    fn push_normalized(normalized: &mut Vec<MatcherToken<'a>>, token: MatcherToken<'a>) {
+        match (normalized.last_mut(), token) {
+            (Some(MatcherToken::RawText(before)), MatcherToken::RawText(after)) => before.to_mut().push_str(&after),
+            (Some(last), token) if token.is_zero_width() && *last == token => {}
+            (_, token) => normalized.push(token),
+        }
+    }
+
+    fn normalize_token(token: MatcherToken<'a>) -> MatcherToken<'a> {
+        let normalize_options = |options: Vec<Vec<MatcherToken<'a>>>| {
+            options.into_iter().map(Self::normalize_sequence).collect()
+        };
+
+        match token {
+            MatcherToken::OneOfText(options) => MatcherToken::OneOfText(normalize_options(options)),
+            MatcherToken::NamedGroup { name, options } => MatcherToken::NamedGroup { name, options: normalize_options(options) },
+            MatcherToken::IgnoreCase(options) => MatcherToken::IgnoreCase(normalize_options(options)),
+            MatcherToken::Optional(inner) => Self::optional(Self::normalize_quantified(*inner)),
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                let inner = Self::normalize_quantified(*inner);
+                match (min, max) {
+                    (1, Some(1)) => inner,
+                    (0, Some(1)) if greedy => Self::optional(inner),
+                    _ => MatcherToken::Repeat { inner: Box::new(inner), min, max, greedy },
+                }
+            }
+            token => token,
+        }
+    }
+
+    /// This normalizes a token a quantifier applies to. A group with just one
+    /// token in it doesn't need to be a group there.
+    fn normalize_quantified(token: MatcherToken<'a>) -> MatcherToken<'a> {
+        match Self::normalize_token(token) {
+            MatcherToken::OneOfText(mut options) if options.len() == 1 && options[0].len() == 1 => {
+                options[0].pop().unwrap_or(MatcherToken::OneOfText(vec![]))
+            }
+            token => token,
+        }
+    }
+
+    /// This makes `inner` optional, unless it already is.
+    fn optional(inner: MatcherToken<'a>) -> MatcherToken<'a> {
+        match inner {
+            MatcherToken::Optional(_) => inner,
+            inner => MatcherToken::Optional(Box::new(inner)),
+        }
+    }
+
+    /// This parses the tokens of a whole pattern, and returns them with whatever
+    /// text is left over. Most patterns have no `|` outside of a group, so the
+    /// list of options is only made once a `|` is found.
+    #[require_lifetimes]
+    fn parse_pattern(
+        text: &'a str,
+        max_alternatives: usize,
+        lazy_groups: bool,
+    ) -> Result<(Vec<MatcherToken<'a>>, &'a str), MatcherParseError> {
+        let (first, leftover) = Self::parse_option(text, text, max_alternatives, lazy_groups)?;
+        let Some(rest) = leftover.strip_prefix('|') else {
+            return Ok((first, leftover));
+        };
+
+        let (options, leftover) = Self::parse_options(text, rest, max_alternatives, lazy_groups, Some(first))?;
+        Ok((vec![Self::group(options)], leftover))
+    }
+
+    /// This makes the token for a group with these options. A group of
+    /// lots of plain words, like one made from a word list, is made into
+    /// a `OneOfWords`, and anything else is left as a `OneOfText`.
+    fn group(mut options: Vec<Vec<MatcherToken<'a>>>) -> MatcherToken<'a> {
+        let is_word = |option: &Vec<MatcherToken>| matches!(option[..], [MatcherToken::RawText(Cow::Borrowed(_))]);
+        if options.len() < MIN_WORD_SET_LEN || !options.iter().all(is_word) {
+            return MatcherToken::OneOfText(options);
+        }
+
+        let words = options
+            .iter_mut()
+            .filter_map(|option| match option.pop() {
+                Some(MatcherToken::RawText(Cow::Borrowed(word))) => Some(word),
+                _ => None,
+            })
+            .collect();
+        MatcherToken::OneOfWords(WordSet::new(words))
+    }
+
+    /// This parses lists of tokens separated by `|` from `unmatched` (which is the
+    /// end of `text`), until the end of the text or a `)`. It returns the lists,
+    /// and the text which is left over, starting with whatever it stopped at.
+    /// There can't be more than `max_alternatives` lists, in here or in a group
+    /// inside them. If the first list was already parsed, it's `first`.
+    #[require_lifetimes]
+    fn parse_options(
+        text: &'a str,
+        unmatched: &'a str,
+        max_alternatives: usize,
+        lazy_groups: bool,
+        first: Option<Vec<MatcherToken<'a>>>,
+    ) -> Result<(Vec<Vec<MatcherToken<'a>>>, &'a str), MatcherParseError> {
+        let mut options: Vec<_> = first.into_iter().collect();
+        let mut unmatched = unmatched;
+        loop {
+            if options.len() == max_alternatives {
+                // This points at the `|` (or `(`) before the option which is one too many.
+                return Err(MatcherParseError::TooManyAlternatives { at: text.len() - unmatched.len() - 1 });
+            }
+            let (option, leftover) = Self::parse_option(text, unmatched, max_alternatives, lazy_groups)?;
+            options.push(option);
+            match leftover.strip_prefix('|') {
+                Some(leftover) => unmatched = leftover,
+                None => return Ok((options, leftover)),
+            }
+        }
+    }
+
+    /// This parses one option of a group, like `parse_tokens`. An option can't be
+    /// empty if there's a `|` next to it, like in `(a||b)` or `(a|)`, since that's
+    /// more likely to be a mistake than a way to match nothing. `(a)?` does that.
+    #[require_lifetimes]
+    fn parse_option(
+        text: &'a str,
+        unmatched: &'a str,
+        max_alternatives: usize,
+        lazy_groups: bool,
+    ) -> Result<(Vec<MatcherToken<'a>>, &'a str), MatcherParseError> {
+        let at = text.len() - unmatched.len();
+        let (option, leftover) = Self::parse_tokens(text, unmatched, max_alternatives, lazy_groups)?;
+        // An option always starts just after a `(`, a `|` or the start of the text.
+        if option.is_empty() && (text[..at].ends_with('|') || leftover.starts_with('|')) {
+            return Err(MatcherParseError::EmptyAlternative { at });
+        }
+
+        Ok((option, leftover))
+    }
+
+    /// This parses tokens from `unmatched` (which is the end of `text`) until the
+    /// end of the text, or until a `)` or `|`. It returns the tokens, and the text
+    /// which is left over, starting with whatever it stopped at. With
+    /// `lazy_groups`, groups of plain text are left as a `LazyGroup`.
+    #[require_lifetimes]
+    fn parse_tokens(
+        text: &'a str,
+        unmatched: &'a str,
+        max_alternatives: usize,
+        lazy_groups: bool,
+    ) -> Result<(Vec<MatcherToken<'a>>, &'a str), MatcherParseError> {
+        let mut tokens: Vec<MatcherToken> = vec![];
+        let mut unmatched = unmatched;
+
+        loop {
+            let at = text.len() - unmatched.len();
+
+            if unmatched.is_empty() || unmatched.starts_with([')', '|']) {
+                break;
+            } else if unmatched.starts_with('.') {
+                tokens.push(MatcherToken::WildCard);
+                unmatched = &unmatched[1..];
+            } else if unmatched.starts_with('^') {
+                tokens.push(MatcherToken::StartAnchor);
+                unmatched = &unmatched[1..];
+            } else if unmatched.starts_with('$') {
+                tokens.push(MatcherToken::EndAnchor);
+                unmatched = &unmatched[1..];
+            } else if unmatched.starts_with('(') {
+                let (name, inside) = match unmatched[1..].strip_prefix("?<") {
+                    Some(named) => {
+                        let (name, inside) = named.split_once('>').ok_or(MatcherParseError::InvalidGroupName { at })?;
+                        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                            return Err(MatcherParseError::InvalidGroupName { at });
+                        }
+                        (Some(name), inside)
+                    }
+                    None => (None, &unmatched[1..]),
+                };
+                let ignore_case = name.is_none() && inside.starts_with("?i:");
+                let inside = if ignore_case { &inside[3..] } else { inside };
+                let plain = if lazy_groups && name.is_none() && !ignore_case { Self::plain_group(inside, max_alternatives) } else { None };
+                if let Some((inside, leftover)) = plain {
+                    tokens.push(MatcherToken::LazyGroup(inside));
+                    unmatched = leftover;
+                    continue;
+                }
+                let (options, leftover) = Self::parse_options(text, inside, max_alternatives, lazy_groups, None)?;
+                // Anything other than `)` here means the group was never closed.
+                unmatched = leftover
+                    .strip_prefix(')')
+                    .ok_or(MatcherParseError::UnbalancedParen { at })?;
+                if options.iter().all(Vec::is_empty) {
+                    return Err(MatcherParseError::EmptyGroup { at });
+                }
+                tokens.push(match name {
+                    Some(name) => MatcherToken::NamedGroup { name, options },
+                    None if ignore_case => MatcherToken::IgnoreCase(options),
+                    None => Self::group(options),
+                });
+            } else if let Some(property) = unmatched.strip_prefix("\\p").or_else(|| unmatched.strip_prefix("\\P")) {
+                let (name, rest) = property
+                    .strip_prefix('{')
+                    .and_then(|inside| inside.split_once('}'))
+                    .ok_or(MatcherParseError::UnknownProperty { at })?;
+                let set = CharSet::property(name).ok_or(MatcherParseError::UnknownProperty { at })?;
+                tokens.push(MatcherToken::CharClass(if unmatched.starts_with("\\P") { set.negate() } else { set }));
+                unmatched = rest;
+            } else if unmatched.starts_with('\\') {
+                let escaped = unmatched[1..]
+                    .chars()
+                    .next()
+                    .ok_or(MatcherParseError::TrailingBackslash { at })?;
+                if escaped == 'b' {
+                    tokens.push(MatcherToken::WordBoundary);
+                } else if let Some(set) = CharSet::shorthand(escaped) {
+                    tokens.push(MatcherToken::CharClass(set));
+                } else if METACHARACTERS.contains(&escaped) {
+                    tokens.push(MatcherToken::RawText(Cow::Borrowed(&unmatched[1..2])));
+                } else {
+                    return Err(MatcherParseError::UnknownEscape { at });
+                }
+                unmatched = &unmatched[1 + escaped.len_utf8()..];
+            } else if unmatched.starts_with('[') {
+                let fc = unmatched.find(']').ok_or(MatcherParseError::UnclosedClass { at })?;
+                let set = Self::parse_class(&unmatched[1..fc]).ok_or(MatcherParseError::BackwardsRange { at })?;
+                tokens.push(MatcherToken::CharClass(set));
+                unmatched = &unmatched[fc + 1..];
+            } else if unmatched.starts_with('?') {
+                let token = Self::pop_quantified(&mut tokens).ok_or(MatcherParseError::NothingToRepeat { at })?;
+                tokens.push(MatcherToken::Optional(Box::new(token)));
+                unmatched = &unmatched[1..];
+            } else if unmatched.starts_with(['*', '+']) {
+                let min = if unmatched.starts_with('+') { 1 } else { 0 };
+                let token = Self::pop_quantified(&mut tokens).ok_or(MatcherParseError::NothingToRepeat { at })?;
+                unmatched = &unmatched[1..];
+                let greedy = !unmatched.starts_with('?');
+                if !greedy {
+                    unmatched = &unmatched[1..];
+                }
+                tokens.push(MatcherToken::Repeat { inner: Box::new(token), min, max: None, greedy });
+            } else if unmatched.starts_with('{') {
+                let fc = unmatched.find('}').ok_or(MatcherParseError::InvalidRepetition { at })?;
+                let (min, max) = Self::parse_bounds(&unmatched[1..fc]).ok_or(MatcherParseError::InvalidRepetition { at })?;
+                let token = Self::pop_quantified(&mut tokens).ok_or(MatcherParseError::NothingToRepeat { at })?;
+                unmatched = &unmatched[fc + 1..];
+                let greedy = !unmatched.starts_with('?');
+                if !greedy {
+                    unmatched = &unmatched[1..];
+                }
+                tokens.push(MatcherToken::Repeat { inner: Box::new(token), min, max, greedy });
+            } else {
+                let first_token = unmatched
+                    .find(|c| TOKEN_STARTS.contains(&c))
+                    .unwrap_or(unmatched.len());
+                tokens.push(MatcherToken::RawText(Cow::Borrowed(&unmatched[..first_token])));
+                unmatched = &unmatched[first_token..];
+            }
+        }
+
+        Ok((tokens, unmatched))
+    }
+
+    /// This splits `inside`, which is just after a `(`, into the text of the group
+    /// and the text left over after its `)`, if the group is plain text which
+    /// `parse_options` would take, like `cat|dog`. Anything else, including any
+    /// mistake, gives `None`, and is left for `parse_options`, so it's an error
+    /// just when it would be for `new`.
+    fn plain_group(inside: &'a str, max_alternatives: usize) -> Option<(&'a str, &'a str)> {
+        let end = inside.find(|c| c != '|' && TOKEN_STARTS.contains(&c))?;
+        let leftover = inside[end..].strip_prefix(')')?;
+        let group = &inside[..end];
+        let fits = group.split('|').count() <= max_alternatives;
+        (fits && !group.split('|').any(str::is_empty)).then_some((group, leftover))
+    }
+
+    /// This removes the token a quantifier like `?` applies to.
+    /// A quantifier only applies to the last character of some
+    /// `RawText`, so that is split off into its own token first.
+    fn pop_quantified(tokens: &mut Vec<MatcherToken<'a>>) -> Option<MatcherToken<'a>> {
+        match tokens.pop()? {
+            MatcherToken::RawText(mut text) => {
+                let (last_start, _) = text.char_indices().last()?;
+                let last = match &mut text {
+                    Cow::Borrowed(text) => {
+                        let (before, last) = text.split_at(last_start);
+                        *text = before;
+                        Cow::Borrowed(last)
+                    }
+                    Cow::Owned(text) => Cow::Owned(text.split_off(last_start)),
+                };
+                if last_start > 0 {
+                    tokens.push(MatcherToken::RawText(text));
+                }
+                Some(MatcherToken::RawText(last))
+            }
+            token => Some(token),
+        }
+    }
+
+    /// This parses the inside of `[...]`. A `-` between two characters
+    /// makes a range, anywhere else it is just a `-`. A leading `^`
+    /// negates the whole set.
+    fn parse_class(class: &str) -> Option<CharSet> {
+        if let Some(negated) = class.strip_prefix('^') {
+            return Some(Self::parse_class(negated)?.negate());
+        }
+
+        let chars: Vec<char> = class.chars().collect();
+        let mut ranges = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                if chars[i] > chars[i + 2] {
+                    return None;
+                }
+                ranges.push((chars[i], chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        Some(CharSet::from_ranges(ranges))
+    }
+
+    /// This parses the inside of `{min,max}`. Either number may be left out,
+    /// and `{n}` means exactly `n` times.
+    fn parse_bounds(bounds: &str) -> Option<(usize, Option<usize>)> {
+        let (min, max) = match bounds.split_once(',') {
+            Some((min, max)) => (min, max),
+            None => (bounds, bounds),
+        };
+        if min.is_empty() && max.is_empty() && !bounds.contains(',') {
+            return None;
+        }
+
+        let min = if min.is_empty() { 0 } else { min.parse().ok()? };
+        let max = if max.is_empty() { None } else { Some(max.parse().ok()?) };
+        if max.is_some_and(|max| min > max) {
+            return None;
+        }
+
+        Some((min, max))
+    }
+
+    /// This should take a string, and return a vector of tokens, and the corresponding part
+    /// of the given string. For examples, see the test cases below.
+    /// It needs `&mut self` to keep track of how far it got, for `tokens_matched` and
+    /// `furthest_byte`. `match_string_ref` matches the same way without doing that.
+    #[require_lifetimes]
+    pub fn match_string <'b, 'c> (&'b mut self, string: &'c str) -> Vec<(&'b MatcherToken<'a>, &'c str)> {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        attempt.substrings(string)
+    }
+
+    /// This is `match_string`, but it gives up once it has tried tokens more than
+    /// `max_steps` times, so a pattern like `(a*)*b` can't take forever
+    /// backtracking. It still updates `tokens_matched` and `furthest_byte` when it
+    /// gives up.
+    #[require_lifetimes]
+    pub fn match_string_budgeted<'b, 'c>(
+        &'b mut self,
+        string: &'c str,
+        max_steps: usize,
+    ) -> Result<Vec<(&'b MatcherToken<'a>, &'c str)>, BudgetExceeded> {
+        let search = Search::with_budget(self.options, max_steps);
+        let attempt = search.match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        if search.exceeded_budget() {
+            return Err(BudgetExceeded { max_steps });
+        }
+        Ok(attempt.substrings(string))
+    }
+
+    /// This is `match_string`, but it also returns how much of the string was
+    /// matched, and whether all of the tokens matched, which `match_string`
+    /// can't tell apart from some of them matching.
+    #[require_lifetimes]
+    pub fn evaluate<'b, 'c>(&'b mut self, string: &'c str) -> MatchResult<'a, 'b, 'c> {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        let (consumed, complete) = (attempt.end, attempt.complete);
+        MatchResult { tokens: attempt.substrings(string), consumed, complete }
+    }
+
+    /// This is `match_string`, but it only needs `&self`, so a matcher behind a
+    /// shared reference, like in an `Arc`, can match from several threads at once.
+    /// It doesn't change `tokens_matched` or `furthest_byte`.
+    #[require_lifetimes]
+    pub fn match_string_ref<'b, 'c>(&'b self, string: &'c str) -> Vec<(&'b MatcherToken<'a>, &'c str)> {
+        Search::new(self.options).match_tokens(&self.tokens, string, 0, false).substrings(string)
+    }
+
+    /// This is `match_string`, but it starts matching at the byte offset `start`,
+    /// rather than at the start of the string. If `start` isn't on a character
+    /// boundary (or is past the end), nothing matches. A `^` still only matches
+    /// at the very start of the whole string.
+    #[require_lifetimes]
+    pub fn match_from<'b, 'c>(&'b mut self, string: &'c str, start: usize) -> Vec<(&'b MatcherToken<'a>, &'c str)> {
+        if !string.is_char_boundary(start) {
+            return vec![];
+        }
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, start, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        attempt.substrings(string)
+    }
+
+    /// This is `match_string`, but it also returns the rest of the
+    /// string, after the part that was matched.
+    #[require_lifetimes]
+    pub fn match_with_remainder<'b, 'c>(&'b mut self, string: &'c str) -> (Vec<(&'b MatcherToken<'a>, &'c str)>, &'c str) {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        let rest = &string[attempt.end..];
+        (attempt.substrings(string), rest)
+    }
+
+    /// This is `match_string`, but with only the parts of the string, in order.
+    pub fn matched_parts<'c>(&mut self, string: &'c str) -> Vec<&'c str> {
+        self.match_string(string).into_iter().map(|(_, part)| part).collect()
+    }
+
+    /// This is `match_string`, but with only the parts `.` tokens matched, in order,
+    /// so `abc.def.` and `abcXdefY` give `X` and `Y`. A `.` inside a group or a
+    /// quantifier isn't a token of its own, so it's part of what that token matched.
+    pub fn wildcard_captures<'c>(&mut self, string: &'c str) -> Vec<&'c str> {
+        self.match_string(string)
+            .into_iter()
+            .filter(|(token, _)| **token == MatcherToken::WildCard)
+            .map(|(_, part)| part)
+            .collect()
+    }
+
+    /// This is the part of the string which `match_string` matched, whether or not
+    /// all the tokens matched. For `abc(d|e)` and `abcx`, it's `abc`.
+    pub fn longest_prefix<'c>(&mut self, string: &'c str) -> &'c str {
+        let Attempt { end, progress, furthest, .. } = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+        self.progress.record(progress, furthest);
+
+        &string[..end]
+    }
+
+    /// This is `match_string`, but it only returns the tokens if they used up the
+    /// whole string. It backtracks to find a way of doing that, so `(a|ab)` fully
+    /// matches `ab`, even though `match_string` would stop after the `a`.
+    #[require_lifetimes]
+    pub fn full_match<'b, 'c>(&'b mut self, string: &'c str) -> Option<Vec<(&'b MatcherToken<'a>, &'c str)>> {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, true);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        if attempt.complete {
+            Some(attempt.substrings(string))
+        } else {
+            None
+        }
+    }
+
+    /// This is `match_string`, but if the tokens didn't all match, it returns the byte
+    /// offset where the next token failed, instead of the tokens which did match.
+    /// That's the end of the attempt which matched the most tokens.
+    #[require_lifetimes]
+    pub fn match_detailed<'b, 'c>(&'b mut self, string: &'c str) -> Result<Vec<(&'b MatcherToken<'a>, &'c str)>, usize> {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        if attempt.complete {
+            Ok(attempt.substrings(string))
+        } else {
+            Err(attempt.end)
+        }
+    }
+
+    /// This is `match_string`, but for each group it also returns the index of the
+    /// option which matched, so for `(cat|dog)` matching `dog` it's `Some(1)`.
+    /// Tokens which aren't groups have `None`.
+    #[require_lifetimes]
+    pub fn match_with_choices<'b, 'c>(&'b mut self, string: &'c str) -> Vec<(&'b MatcherToken<'a>, &'c str, Option<usize>)> {
+        let search = Search::new(self.options);
+        let attempt = search.match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        attempt
+            .answer
+            .into_iter()
+            .filter(|(_, range)| !range.is_empty())
+            .map(|(token, range)| (token, &string[range.clone()], search.choice(token, string, range)))
+            .collect()
+    }
+
+    /// This is `match_with_choices`, but with the tokens of the option each group
+    /// used, as well as its index. A group of words which was made into a
+    /// `OneOfWords`, or a `LazyGroup`, has no tokens for its options, so like
+    /// tokens which aren't groups, it has `None`.
+    #[require_lifetimes]
+    pub fn match_with_alternatives<'b, 'c>(&'b mut self, string: &'c str) -> Vec<(&'b MatcherToken<'a>, &'c str, Option<Alternative<'a, 'b>>)> {
+        self.match_with_choices(string)
+            .into_iter()
+            .map(|(token, part, choice)| {
+                let alternative = match (token, choice) {
+                    (
+                        MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options),
+                        Some(index),
+                    ) => Some(Alternative { index, tokens: &options[index] }),
+                    _ => None,
+                };
+                (token, part, alternative)
+            })
+            .collect()
+    }
+
+    /// This is `match_string`, but it returns the range of bytes each token matched,
+    /// which is handy for highlighting them. Tokens which match without using up any
+    /// of the string, like `^` or a `?` which matched nothing, have an empty range.
+    pub fn match_indices(&mut self, string: &str) -> Vec<(&MatcherToken<'a>, Range<usize>)> {
+        let attempt = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+
+        self.progress.record(attempt.progress, attempt.furthest);
+
+        attempt.answer
+    }
+
+    /// This matches like `match_string`, but only returns how many bytes the tokens
+    /// used up, or `None` if they didn't all match. It still updates `tokens_matched`
+    /// and `furthest_byte`, but it doesn't allocate, except to look through a `OneOfWords`.
+    pub fn match_len(&mut self, string: &str) -> Option<usize> {
+        let search = Search::new(self.options);
+        let consumed = Cell::new(0);
+        let mut len = None;
+        search.match_counted(&self.tokens, string, 0, 0, &consumed, &mut |end| {
+            len = Some(end);
+            true
+        });
+        self.progress.record(consumed.get().max(search.partial_progress.get()), search.furthest.get());
+        len
+    }
+
+    /// This returns `true` only if every token matched, and the
+    /// whole of the given string was used up doing so.
+    pub fn is_match(&mut self, string: &str) -> bool {
+        let Attempt { complete, progress, furthest, .. } = Search::new(self.options).match_tokens(&self.tokens, string, 0, true);
+        self.progress.record(progress, furthest);
+
+        complete
+    }
+
+    /// This returns `true` if every token matched the start of the string, even
+    /// if there's more of the string after them, unlike `is_match`.
+    pub fn is_prefix_match(&mut self, string: &str) -> bool {
+        let Attempt { complete, progress, furthest, .. } = Search::new(self.options).match_tokens(&self.tokens, string, 0, false);
+        self.progress.record(progress, furthest);
+
+        complete
+    }
+
+    /// This is whether `a` and `b` agree about which of the samples they match the
+    /// whole of. It's only as good as the samples, so it can't prove that two
+    /// patterns match the same strings, but it's useful for checking a rewrite.
+    pub fn accepts_same(a: &mut Matcher, b: &mut Matcher, samples: &[&str]) -> bool {
+        samples.iter().all(|sample| a.is_match(sample) == b.is_match(sample))
+    }
+
+    /// This looks for the first place in the haystack that the tokens match,
+    /// and returns the start and end of that match as byte offsets.
+    pub fn find(&mut self, haystack: &str) -> Option<(usize, usize)> {
+        self.find_from(haystack, 0)
+    }
+
+    /// This finds the first match in the string, like `find`, and returns what each
+    /// named group matched, by name. A group which wasn't part of the match, like one
+    /// in an option that wasn't taken, isn't in the map. If a group matched more than
+    /// once, like `(?<digit>\d)+`, the map has what it matched last.
+    #[cfg(feature = "std")]
+    pub fn captures<'c>(&mut self, string: &'c str) -> Option<HashMap<String, &'c str>> {
+        let (start, _) = self.find(string)?;
+        Some(self.captures_at(string, start))
+    }
+
+    /// This is `captures` for every match `find_all` finds. The captured text is
+    /// copied, so the maps don't borrow from the haystack.
+    #[cfg(feature = "std")]
+    pub fn captures_iter(&mut self, haystack: &str) -> Vec<HashMap<String, String>> {
+        let matches = self.find_all(haystack);
+        matches
+            .into_iter()
+            .map(|(start, _)| {
+                let captures = self.captures_at(haystack, start);
+                captures.into_iter().map(|(name, text)| (name, text.to_string())).collect()
+            })
+            .collect()
+    }
+
+    /// This is what each named group matched in the match which starts at `start`.
+    #[cfg(feature = "std")]
+    pub fn captures_at<'c>(&self, string: &'c str, start: usize) -> HashMap<String, &'c str> {
+        let search = Search::new(self.options);
+        search.match_tokens(&self.tokens, string, start, false);
+
+        let captures = search.captures.into_inner();
+        captures.into_iter().map(|(name, text)| (name.to_string(), text)).collect()
+    }
+
+    /// This returns every match in the haystack, from left to right. Each
+    /// search starts after the previous match, so they don't overlap.
+    pub fn find_all(&mut self, haystack: &str) -> Vec<(usize, usize)> {
+        self.matches_iter(haystack).collect()
+    }
+
+    /// This returns a match for every place in the haystack that one starts, even
+    /// if it overlaps the one before, so `aa` is found three times in `aaaa`.
+    /// Unlike `find_all`, the next search always starts one character later.
+    pub fn find_all_overlapping(&mut self, haystack: &str) -> Vec<(usize, usize)> {
+        let starts = haystack.char_indices().map(|(start, _)| start).chain(core::iter::once(haystack.len()));
+        let mut matches = vec![];
+
+        for start in starts {
+            let Attempt { end, complete, progress, furthest, .. } =
+                Search::new(self.options).match_tokens(&self.tokens, haystack, start, false);
+            self.progress.record(progress, furthest);
+            if complete && self.options.keeps_match(haystack, (start, end)) {
+                matches.push((start, end));
+            }
+        }
+
+        matches
+    }
+
+    /// This is, for every place a match could start, the byte offset and whether
+    /// one does start there. Like `find_all_overlapping`, that's each character
+    /// boundary, including the end of the haystack.
+    pub fn match_mask(&mut self, haystack: &str) -> Vec<(usize, bool)> {
+        let starts = haystack.char_indices().map(|(start, _)| start).chain(core::iter::once(haystack.len()));
+
+        starts
+            .map(|start| {
+                let Attempt { end, complete, progress, furthest, .. } =
+                    Search::new(self.options).match_tokens(&self.tokens, haystack, start, false);
+                self.progress.record(progress, furthest);
+                (start, complete && self.options.keeps_match(haystack, (start, end)))
+            })
+            .collect()
+    }
+
+    /// This is how many matches `find_all` would find, without keeping them.
+    pub fn count_matches(&mut self, haystack: &str) -> usize {
+        self.matches_iter(haystack).count()
+    }
+
+    /// This is `find_all` for text read from `reader`, like a log file too big to read
+    /// all at once. It finds the same matches `find_all` would if all of it was read
+    /// into one string first, so a match can go over a line break, and `^` and `$`
+    /// are the start and end of everything read. Only what's been read since the last
+    /// place a match could still start is kept, which is more than one read when a
+    /// match needs to see further to know where it ends. The matches are byte offsets
+    /// from the start of what was read. Text which isn't UTF-8 is an
+    /// `io::ErrorKind::InvalidData`.
+    #[cfg(feature = "std")]
+    pub fn find_all_in_reader(&mut self, mut reader: impl BufRead) -> io::Result<Vec<(usize, usize)>> {
+        let mut matches = vec![];
+        // This is what's been read from byte `offset` on, apart from the start of a
+        // char which was cut off at the end of the last read, which is `unfinished`.
+        let mut buffer = String::new();
+        let mut unfinished = vec![];
+        let mut offset = 0;
+        let mut from = 0;
+        let mut more = true;
+
+        loop {
+            match self.find_in_stream(&buffer, offset, from, more) {
+                Ok(Some(found)) => {
+                    matches.push((offset + found.0, offset + found.1));
+                    match Self::resume_after(&buffer, found) {
+                        Some(next) => from = next,
+                        None => break,
+                    }
+                }
+                Ok(None) => break,
+                Err(start) => {
+                    // Nothing before `start` can be part of a match any more, but the
+                    // char just before it is kept for `\b` to look at.
+                    let keep = buffer[..start].char_indices().next_back().map_or(0, |(i, _)| i);
+                    buffer.drain(..keep);
+                    offset += keep;
+                    from = start - keep;
+                    more = Self::read_more(&mut reader, &mut buffer, &mut unfinished)?;
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// This is `find_from` for the part of a stream in `buffer`, which starts at
+    /// byte `offset` of the stream. If there's `more` of the stream after `buffer`,
+    /// a start where the search ran into the end of `buffer` can't be decided yet,
+    /// so it's the error, to be tried again once more has been read. Every start
+    /// before it didn't match.
+    #[cfg(feature = "std")]
+    fn find_in_stream(&mut self, buffer: &str, offset: usize, from: usize, more: bool) -> Result<Option<(usize, usize)>, usize> {
+        let anchored = self.is_anchored().0;
+        if anchored && offset + from > 0 {
+            return Ok(None);
+        }
+        let starts = buffer[from..]
+            .char_indices()
+            .map(|(i, _)| from + i)
+            .chain(core::iter::once(buffer.len()))
+            .take_while(|&start| !anchored || start == 0);
+
+        for start in starts {
+            // A match of nothing here would need the next char to know where to go on from.
+            if more && start == buffer.len() {
+                return Err(start);
+            }
+            let search = if more { Search::in_stream(self.options) } else { Search::new(self.options) };
+            let Attempt { end, complete, progress, furthest, .. } = search.match_tokens(&self.tokens, buffer, start, false);
+            self.progress.record(progress, offset + furthest);
+            if search.ran_out() || (more && complete && self.options.whole_words && end == buffer.len()) {
+                return Err(start);
+            }
+            if complete && self.options.keeps_match(buffer, (start, end)) {
+                return Ok(Some((start, end)));
+            }
+        }
+
+        if more { Err(buffer.len()) } else { Ok(None) }
+    }
+
+    /// This reads the next part of `reader` onto the end of `buffer`, and returns
+    /// whether there might be more after it. A char which is cut off at the end is
+    /// left in `unfinished` until the rest of it is read.
+    #[cfg(feature = "std")]
+    fn read_more(reader: &mut impl BufRead, buffer: &mut String, unfinished: &mut Vec<u8>) -> io::Result<bool> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+        let read = reader.fill_buf()?;
+        if read.is_empty() {
+            return if unfinished.is_empty() { Ok(false) } else { Err(invalid()) };
+        }
+        unfinished.extend_from_slice(read);
+        let len = read.len();
+        reader.consume(len);
+
+        let valid = match core::str::from_utf8(unfinished) {
+            Ok(text) => text.len(),
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            Err(_) => return Err(invalid()),
+        };
+        buffer.push_str(core::str::from_utf8(&unfinished[..valid]).map_err(|_| invalid())?);
+        unfinished.drain(..valid);
+        Ok(true)
+    }
+
+    /// This is `find_all`, but it only looks for each match as it's needed.
+    #[require_lifetimes]
+    pub fn matches_iter<'m, 'h>(&'m mut self, haystack: &'h str) -> Matches<'m, 'a, 'h> {
+        Matches { matcher: self, haystack, from: Some(0) }
+    }
+
+    /// This copies the haystack, but with just the first match swapped for
+    /// `replacement`, like `str::replacen` with a count of 1. The replacement is
+    /// copied as it is. `try_replace` is the same, but the replacement can refer
+    /// to what matched.
+    pub fn replace(&mut self, haystack: &str, replacement: &str) -> String {
+        self.substitute(haystack, &[Replacement::Text(replacement)], false)
+    }
+
+    /// This copies the haystack, but with every match swapped for `replacement`.
+    /// The replacement is copied as it is. `try_replace_all` is the same, but the
+    /// replacement can refer to what matched.
+    pub fn replace_all(&mut self, haystack: &str, replacement: &str) -> String {
+        self.substitute(haystack, &[Replacement::Text(replacement)], true)
+    }
+
+    /// This is `replace`, but the replacement can refer to what matched, the same
+    /// way as for `try_replace_all`.
+    pub fn try_replace(&mut self, haystack: &str, replacement: &str) -> Result<String, ReplacementError> {
+        let template = self.template(replacement)?;
+        Ok(self.substitute(haystack, &template, false))
+    }
+
+    /// This is `replace_all`, but in the replacement, `$0` is the whole match,
+    /// `$name` is what the group `(?<name>...)` matched, and `$$` is a `$`. A
+    /// group which wasn't part of the match gives nothing. A `$` which doesn't
+    /// refer to a group in the pattern is an error.
+    pub fn try_replace_all(&mut self, haystack: &str, replacement: &str) -> Result<String, ReplacementError> {
+        let template = self.template(replacement)?;
+        Ok(self.substitute(haystack, &template, true))
+    }
+
+    /// This copies the haystack, with the first match, or `all` of them, swapped
+    /// for what the template gives for it.
+    fn substitute(&mut self, haystack: &str, template: &[Replacement], all: bool) -> String {
+        let matches = if all { self.find_all(haystack) } else { self.find(haystack).into_iter().collect() };
+        let mut replaced = String::with_capacity(haystack.len());
+        let mut copied_up_to = 0;
+
+        for (start, end) in matches {
+            replaced.push_str(&haystack[copied_up_to..start]);
+            self.expand(template, haystack, (start, end), &mut replaced);
+            copied_up_to = end;
+        }
+        replaced.push_str(&haystack[copied_up_to..]);
+
+        replaced
+    }
+
+    /// This splits a replacement into text and the `$` references in it, and checks
+    /// that every group it refers to is in the pattern.
+    #[require_lifetimes]
+    fn template<'r>(&self, replacement: &'r str) -> Result<Vec<Replacement<'r>>, ReplacementError> {
+        let mut template = vec![];
+        let mut unmatched = replacement;
+
+        while let Some(dollar) = unmatched.find('$') {
+            let at = replacement.len() - unmatched.len() + dollar;
+            template.push(Replacement::Text(&unmatched[..dollar]));
+            let reference = &unmatched[dollar + 1..];
+            if let Some(rest) = reference.strip_prefix('$') {
+                template.push(Replacement::Text("$"));
+                unmatched = rest;
+                continue;
+            }
+
+            let name_len = reference.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(reference.len());
+            let (name, rest) = reference.split_at(name_len);
+            template.push(match name {
+                "" => return Err(ReplacementError::MissingGroupName { at }),
+                "0" => Replacement::Match,
+                name if Self::has_group(&self.tokens, name) => Replacement::Group(name),
+                _ => return Err(ReplacementError::UnknownGroup { at }),
+            });
+            unmatched = rest;
+        }
+        template.push(Replacement::Text(unmatched));
+
+        Ok(template)
+    }
+
+    /// This is whether there's a group called `name` anywhere in the tokens.
+    fn has_group(tokens: &[MatcherToken], name: &str) -> bool {
+        let in_options = |options: &[Vec<MatcherToken>]| options.iter().any(|option| Self::has_group(option, name));
+
+        tokens.iter().any(|token| match token {
+            MatcherToken::NamedGroup { name: group, options } => *group == name || in_options(options),
+            MatcherToken::OneOfText(options) | MatcherToken::IgnoreCase(options) => in_options(options),
+            MatcherToken::Optional(inner) | MatcherToken::Repeat { inner, .. } => {
+                Self::has_group(core::slice::from_ref(&**inner), name)
+            }
+            _ => false,
+        })
+    }
+
+    /// This writes out the template for the match from `start` to `end`. The
+    /// groups are only matched again if the template refers to one of them.
+    fn expand(&self, template: &[Replacement], haystack: &str, (start, end): (usize, usize), replaced: &mut String) {
+        let captures = if template.iter().any(|piece| matches!(piece, Replacement::Group(_))) {
+            let search = Search::new(self.options);
+            search.match_tokens(&self.tokens, haystack, start, false);
+            search.captures.into_inner()
+        } else {
+            vec![]
+        };
+
+        for piece in template {
+            match piece {
+                Replacement::Text(text) => replaced.push_str(text),
+                Replacement::Match => replaced.push_str(&haystack[start..end]),
+                Replacement::Group(name) => {
+                    // As with `captures`, a group which matched more than once gives what it matched last.
+                    if let Some((_, text)) = captures.iter().rev().find(|(group, _)| group == name) {
+                        replaced.push_str(text);
+                    }
+                }
+            }
+        }
+    }
+
+    /// This splits the haystack into the pieces between matches, like `str::split`.
+    /// A match at the start or end gives an empty piece there. Matches of nothing
+    /// are found at most once per character, so this always finishes.
+    pub fn split<'h>(&mut self, haystack: &'h str) -> Vec<&'h str> {
+        let mut pieces = vec![];
+        let mut piece_start = 0;
+
+        for (start, end) in self.matches_iter(haystack) {
+            pieces.push(&haystack[piece_start..start]);
+            piece_start = end;
+        }
+        pieces.push(&haystack[piece_start..]);
+
+        pieces
+    }
+
+    /// This is where to keep searching after a match. It's usually the end
+    /// of the match, but a match of nothing needs to skip a character, or
+    /// it would be found again forever.
+    fn resume_after(haystack: &str, (start, end): (usize, usize)) -> Option<usize> {
+        if end > start {
+            Some(end)
+        } else {
+            haystack[end..].chars().next().map(|c| end + c.len_utf8())
+        }
+    }
+
+    /// This is `find`, but it only looks at matches starting at `from` or later.
+    /// A pattern starting with `^` can only match at the start of the haystack, so
+    /// nowhere else is tried.
+    pub fn find_from(&mut self, haystack: &str, from: usize) -> Option<(usize, usize)> {
+        let last_start = if self.is_anchored().0 { 0 } else { haystack.len() };
+        let starts = haystack[from..]
+            .char_indices()
+            .map(|(offset, _)| from + offset)
+            .chain(core::iter::once(haystack.len()))
+            .take_while(|&start| start <= last_start);
+
+        for start in starts {
+            let Attempt { end, complete, progress, furthest, .. } =
+                Search::new(self.options).match_tokens(&self.tokens, haystack, start, false);
+            self.progress.record(progress, furthest);
+            if complete && self.options.keeps_match(haystack, (start, end)) {
+                return Some((start, end));
+            }
+        }
+
+        None
+    }
+
+    /// This is the tokens the pattern was parsed into, to look through without
+    /// depending on how the matcher stores them.
+    #[require_lifetimes]
+    pub fn tokens<'b>(&'b self) -> &'b [MatcherToken<'a>] {
+        &self.tokens
+    }
+
+    /// This is how many tokens the pattern was parsed into. A group counts as
+    /// one token, however much is inside it.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// This is whether the pattern has no tokens at all, so it matches the
+    /// start of any string.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// This is whether the pattern can match without using up any of the string,
+    /// like `a*` or `^` can. Those are the patterns which `find_all` and `split`
+    /// can find matches of nothing with. Anchors and `\b` count, since they can
+    /// match somewhere even if not in every string, like `$` in `ab`.
+    pub fn matches_empty(&self) -> bool {
+        self.tokens.iter().all(MatcherToken::matches_empty)
+    }
+
+    /// This is the fewest and the most bytes a match can be, worked out from the
+    /// tokens without matching anything. The most is `None` if there's no limit,
+    /// like with `a*`.
+    pub fn match_length_bounds(&self) -> (usize, Option<usize>) {
+        MatcherToken::sequence_length_bounds(&self.tokens, &self.options)
+    }
+
+    /// This lists the tokens one per line, numbered from 0, like `1: OneOfText([d,e,f])`.
+    /// It's easier to read than the derived `Debug` for a big pattern, since what's
+    /// inside each token is written in the pattern's own syntax.
+    pub fn debug_tokens(&self) -> String {
+        let lines: Vec<String> = self.tokens.iter().enumerate().map(|(index, token)| format!("{index}: {}", token.debug_line())).collect();
+        lines.join("\n")
+    }
+
+    /// This is whether every match is the same number of characters long, like
+    /// for `abc.`, but not `(a|bb)` or `a*`. A search for such a pattern knows
+    /// how far a match goes before trying it.
+    pub fn is_fixed_width(&self) -> bool {
+        MatcherToken::sequence_fixed_width(&self.tokens, &self.options).is_some()
+    }
+
+    /// This is whether the pattern starts with `^`, and whether it ends with `$`.
+    pub fn is_anchored(&self) -> (bool, bool) {
+        let starts = matches!(self.tokens.first(), Some(MatcherToken::StartAnchor));
+        let ends = matches!(self.tokens.last(), Some(MatcherToken::EndAnchor));
+        (starts, ends)
+    }
+
+    /// This is the most tokens this matcher has matched so far.
+    pub fn tokens_matched(&self) -> usize {
+        self.progress.most_tokens_matched
+    }
+
+    /// This is the furthest byte offset into a string this matcher has got to so
+    /// far, so for an error message it can point at where a string stopped matching.
+    pub fn furthest_byte(&self) -> usize {
+        self.progress.most_bytes_matched
+    }
+
+    /// This forgets how many tokens have been matched, and how far,
+    /// so it can be measured again for a new batch of strings.
+    pub fn reset_progress(&mut self) {
+        self.progress = Progress::default();
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Matcher<'a> {
+    type Error = MatcherParseError;
+
+    fn try_from(text: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        Matcher::new(text)
+    }
+}
+
+/// This goes through the matcher's tokens in order, so `for token in &matcher`
+/// sees each one.
+impl<'m, 'a> IntoIterator for &'m Matcher<'a> {
+    type Item = &'m MatcherToken<'a>;
+    type IntoIter = core::slice::Iter<'m, MatcherToken<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tokens.iter()
+    }
+}
+
+/// This builds a `Matcher` with whichever options are chosen, like
+/// `MatcherBuilder::new().case_insensitive(true).build("(cat|dog)")`.
+/// Options which aren't chosen are off, as they are for `Matcher::new`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatcherBuilder {
+    options: MatchOptions,
+    max_alternatives: Option<usize>,
+    trim_whitespace: bool,
+    lazy_groups: bool,
+}
+
+impl MatcherBuilder {
+    pub fn new() -> MatcherBuilder {
+        MatcherBuilder::default()
+    }
+
+    /// This sets whether matching ignores ASCII case.
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> MatcherBuilder {
+        self.options.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// This sets whether a `.` matches a `\n`.
+    pub fn dot_matches_newline(mut self, dot_matches_newline: bool) -> MatcherBuilder {
+        self.options.dot_matches_newline = dot_matches_newline;
+        self
+    }
+
+    /// This sets whether a `.` matches a whole grapheme, rather than one `char`.
+    pub fn dot_matches_grapheme(mut self, dot_matches_grapheme: bool) -> MatcherBuilder {
+        self.options.dot_matches_grapheme = dot_matches_grapheme;
+        self
+    }
+
+    /// This sets which characters a `.` matches.
+    pub fn wildcard(mut self, wildcard: fn(char) -> bool) -> MatcherBuilder {
+        self.options.wildcard = Some(Wildcard(wildcard));
+        self
+    }
+
+    /// This sets a function to be told about each token as it's tried.
+    pub fn trace(mut self, trace: fn(&MatcherToken, usize, Option<&str>)) -> MatcherBuilder {
+        self.options.trace = Some(Trace(trace));
+        self
+    }
+
+    /// This sets whether searches only find matches which are whole words.
+    pub fn whole_words(mut self, whole_words: bool) -> MatcherBuilder {
+        self.options.whole_words = whole_words;
+        self
+    }
+
+    /// This sets whether whitespace at the start and end of the pattern is left
+    /// out, like a pattern read from a config file might have. It's off by default,
+    /// since it'd otherwise be matched like any other text. Byte offsets in errors
+    /// are from the start of the trimmed pattern.
+    pub fn trim_whitespace(mut self, trim_whitespace: bool) -> MatcherBuilder {
+        self.trim_whitespace = trim_whitespace;
+        self
+    }
+
+    /// This sets whether part of a group's option counts towards how far matching got.
+    pub fn partial_alternatives(mut self, partial_alternatives: bool) -> MatcherBuilder {
+        self.options.partial_alternatives = partial_alternatives;
+        self
+    }
+
+    /// This sets the most options a group can have before the pattern is an error.
+    pub fn max_alternatives(mut self, max_alternatives: usize) -> MatcherBuilder {
+        self.max_alternatives = Some(max_alternatives);
+        self
+    }
+
+    /// This sets whether groups of plain text are kept as their text and split
+    /// while matching, like `Matcher::new_lazy` does.
+    pub fn lazy_groups(mut self, lazy_groups: bool) -> MatcherBuilder {
+        self.lazy_groups = lazy_groups;
+        self
+    }
+
+    /// This sets whether the options of groups are sorted longest first.
+    pub fn longest_alternative_first(mut self, longest_alternative_first: bool) -> MatcherBuilder {
+        self.options.longest_alternative_first = longest_alternative_first;
+        self
+    }
+
+    /// This parses the pattern into a `Matcher` with the options chosen so far.
+    /// The builder can be used again to build more matchers.
+    #[require_lifetimes]
+    pub fn build<'a>(&self, pattern: &'a str) -> Result<Matcher<'a>, MatcherParseError> {
+        let pattern = if self.trim_whitespace { pattern.trim() } else { pattern };
+        let mut matcher = Matcher::parse(pattern, self.max_alternatives.unwrap_or(usize::MAX), self.lazy_groups)?;
+        matcher.set_options(self.options);
+        Ok(matcher)
+    }
+}
+
+/// This is whether `position` in `string` is between a word character (an ASCII
+/// letter or digit, or `_`) and something which isn't one, for `\b`.
+fn is_word_boundary(string: &str, position: usize) -> bool {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+    is_word(string[..position].chars().next_back()) != is_word(string[position..].chars().next())
+}
+
+/// This is how many bytes the first grapheme at the start of `text` is, meaning
+/// what's shown as one character. It's close to, but simpler than, Unicode's
+/// extended grapheme clusters: it keeps `\r\n`, a pair of flag letters, and
+/// a `char` followed by combining marks, variation selectors, skin tones, tags
+/// and anything joined to it with a zero width joiner together. With the
+/// `unicode-segmentation` feature, the real extended grapheme clusters are used.
+#[cfg(not(feature = "unicode-segmentation"))]
+fn grapheme_len(text: &str) -> usize {
+    let is_regional_indicator = |c: char| ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+    let is_extend = |c: char| {
+        matches!(c,
+            '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{200C}'..='\u{200D}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+            | '\u{E0020}'..='\u{E007F}'
+            | '\u{E0100}'..='\u{E01EF}')
+    };
+
+    let mut chars = text.chars().peekable();
+    let Some(first) = chars.next() else {
+        return 0;
+    };
+    let mut len = first.len_utf8();
+    match chars.peek() {
+        Some('\n') if first == '\r' => return 2,
+        Some(&second) if is_regional_indicator(first) && is_regional_indicator(second) => {
+            len += second.len_utf8();
+            chars.next();
+        }
+        _ => {}
+    }
+
+    let mut joined = false;
+    while let Some(&c) = chars.peek() {
+        if (!joined || c == '\n') && !is_extend(c) {
+            break;
+        }
+        joined = c == '\u{200D}';
+        len += c.len_utf8();
+        chars.next();
+    }
+
+    len
+}
+
+/// This is how many bytes the first extended grapheme cluster at the start of
+/// `text` is, from the `unicode-segmentation` crate.
+#[cfg(feature = "unicode-segmentation")]
+fn grapheme_len(text: &str) -> usize {
+    use unicode_segmentation::UnicodeSegmentation;
+    text.graphemes(true).next().map_or(0, str::len)
+}
+
+/// This is a single search through a string, with the options to match with
+/// and the named groups which have matched so far, as their name and the text
+/// they matched. Groups are added as they match, and taken off again if the
+/// search backtracks past them, so after a match they are all still there.
+struct Search<'a, 'c> {
+    options: MatchOptions,
+    /// This is whether case is ignored where the search is up to. It's
+    /// `options.case_insensitive`, except inside a `(?i:...)` group.
+    ignore_case: Cell<bool>,
+    captures: RefCell<Vec<(&'a str, &'c str)>>,
+    /// This is the furthest byte offset in the string that any attempt got to.
+    furthest: Cell<usize>,
+    /// This is how many times a token has been tried. Once it's more than
+    /// `max_steps`, nothing else matches, so the search gives up quickly.
+    steps: Cell<usize>,
+    max_steps: usize,
+    /// This is the most tokens matched, counting a group which only partly
+    /// matched, for `MatchOptions::partial_alternatives`.
+    partial_progress: Cell<usize>,
+    /// This is whether the string is only the start of a stream, so there might
+    /// be more after it. Then `ran_out` is set if a token was tried at the end in
+    /// a way which more text could have changed.
+    streaming: bool,
+    ran_out: Cell<bool>,
+}
+
+impl<'a, 'c> Search<'a, 'c> {
+    fn new(options: MatchOptions) -> Search<'a, 'c> {
+        Search {
+            options,
+            ignore_case: Cell::new(options.case_insensitive),
+            captures: RefCell::new(vec![]),
+            furthest: Cell::new(0),
+            steps: Cell::new(0),
+            max_steps: usize::MAX,
+            partial_progress: Cell::new(0),
+            streaming: false,
+            ran_out: Cell::new(false),
+        }
+    }
+
+    /// This is a search of the start of a stream, for `Matcher::find_all_in_reader`.
+    #[cfg(feature = "std")]
+    fn in_stream(options: MatchOptions) -> Search<'a, 'c> {
+        Search { streaming: true, ..Search::new(options) }
+    }
+
+    /// This is whether the search of a stream needs to see more of it to be sure.
+    #[cfg(feature = "std")]
+    fn ran_out(&self) -> bool {
+        self.ran_out.get()
+    }
+
+    /// This is whether `token` might match differently at the start of `unmatched`
+    /// if there was more text after it. Only tokens which look at the text
+    /// themselves can, since any others are made of them.
+    fn runs_out(&self, token: &MatcherToken<'a>, unmatched: &str) -> bool {
+        let cut_off = |text: &str| text.len() > unmatched.len() && self.starts_with(text, unmatched);
+        match token {
+            MatcherToken::RawText(text) => cut_off(text),
+            MatcherToken::OneOfWords(set) => set.words.iter().any(|word| cut_off(word)),
+            MatcherToken::LazyGroup(inside) => inside.split('|').any(cut_off),
+            MatcherToken::WildCard if self.options.dot_matches_grapheme => grapheme_len(unmatched) == unmatched.len(),
+            MatcherToken::WildCard | MatcherToken::CharClass(_) | MatcherToken::EndAnchor | MatcherToken::WordBoundary => {
+                unmatched.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// This is a search which gives up after trying tokens `max_steps` times.
+    fn with_budget(options: MatchOptions, max_steps: usize) -> Search<'a, 'c> {
+        Search { max_steps, ..Search::new(options) }
+    }
+
+    /// This is whether the search tried more steps than it was allowed.
+    fn exceeded_budget(&self) -> bool {
+        self.steps.get() > self.max_steps
+    }
+
+    /// This matches the tokens one after another against the string, starting at
+    /// `start`. If `full` is set, the tokens also have to use up the rest of the string.
+    #[require_lifetimes]
+    fn match_tokens<'b>(&self, tokens: &'b [MatcherToken<'a>], string: &'c str, start: usize, full: bool) -> Attempt<'a, 'b> {
+        let mut path = Path::default();
+        let mut best = (Path::default(), start);
+        let mut end = start;
+
+        let complete = self.match_path(tokens, string, start, &mut path, &mut best, &mut |position| {
+            if full && position != string.len() {
+                return false;
+            }
+            end = position;
+            true
+        });
+
+        let progress = best.0.consumed.max(self.partial_progress.get());
+        let furthest = self.furthest.get();
+        if complete {
+            Attempt { answer: path.steps, end, complete, progress, furthest }
+        } else {
+            Attempt { answer: best.0.steps, end: best.1, complete, progress, furthest }
+        }
+    }
+
+    /// This tries each way the first token can match, recording what it matched in
+    /// `path` and then moving on to the rest of the tokens. If they can't match
+    /// afterwards, it backtracks and tries the next way. `best` keeps the `path`
+    /// seen which consumed the most tokens and where it ended, and `accept` decides
+    /// whether a match that ends at some position is good enough.
+    fn match_path<'b>(
+        &self,
+        tokens: &'b [MatcherToken<'a>],
+        string: &'c str,
+        position: usize,
+        path: &mut Path<'a, 'b>,
+        best: &mut (Path<'a, 'b>, usize),
+        accept: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        if path.consumed > best.0.consumed {
+            *best = (path.clone(), position);
+        }
+        self.furthest.set(self.furthest.get().max(position));
+
+        let Some((token, rest)) = tokens.split_first() else {
+            return accept(position);
+        };
+
+        let mut matched = false;
+        let found = self.match_token(token, string, position, &mut |end| {
+            matched = true;
+            path.push(token, position..end);
+            if self.match_path(rest, string, end, path, best, accept) {
+                return true;
+            }
+            path.pop();
+            false
+        });
+        if !matched && self.options.partial_alternatives {
+            self.record_partial(token, &string[position..], position, path.consumed);
+        }
+        found
+    }
+
+    /// This is `match_path`, but it only counts how many of the tokens have used up
+    /// some text, rather than keeping what each one matched. The most it got to is
+    /// kept in `most_consumed`.
+    fn match_counted(
+        &self,
+        tokens: &[MatcherToken<'a>],
+        string: &'c str,
+        position: usize,
+        consumed: usize,
+        most_consumed: &Cell<usize>,
+        next: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        most_consumed.set(most_consumed.get().max(consumed));
+        self.furthest.set(self.furthest.get().max(position));
+
+        let Some((token, rest)) = tokens.split_first() else {
+            return next(position);
+        };
+
+        let mut matched = false;
+        let found = self.match_token(token, string, position, &mut |end| {
+            matched = true;
+            self.match_counted(rest, string, end, consumed + usize::from(end > position), most_consumed, next)
+        });
+        if !matched && self.options.partial_alternatives {
+            self.record_partial(token, &string[position..], position, consumed);
+        }
+        found
+    }
+
+    /// This counts a group which couldn't match at `position` as partly matched,
+    /// if `unmatched` starts with part of the text one of its options starts with.
+    /// `consumed` is how many tokens matched before it.
+    fn record_partial(&self, token: &MatcherToken<'a>, unmatched: &str, position: usize, consumed: usize) {
+        let ignore_case = self.ignore_case.get() || matches!(token, MatcherToken::IgnoreCase(_));
+        let common_len = |text: &str| {
+            unmatched
+                .char_indices()
+                .zip(text.chars())
+                .take_while(|&((_, a), b)| a == b || (ignore_case && a.eq_ignore_ascii_case(&b)))
+                .last()
+                .map_or(0, |((start, c), _)| start + c.len_utf8())
+        };
+        let starting_text = |option: &Vec<MatcherToken<'a>>| match option.first() {
+            Some(MatcherToken::RawText(text)) => common_len(text),
+            _ => 0,
+        };
+
+        let longest = match token {
+            MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => {
+                options.iter().map(starting_text).max()
+            }
+            MatcherToken::OneOfWords(set) => set.words.iter().map(|word| common_len(word)).max(),
+            MatcherToken::LazyGroup(inside) => inside.split('|').map(common_len).max(),
+            _ => None,
+        };
+        if let Some(longest) = longest.filter(|&longest| longest > 0) {
+            self.partial_progress.set(self.partial_progress.get().max(consumed + 1));
+            self.furthest.set(self.furthest.get().max(position + longest));
+        }
+    }
+
+    /// This calls `next` with the position after each list of tokens has matched, one
+    /// after another, starting at `position`. It stops as soon as `next` returns `true`.
+    fn match_sequence(&self, tokens: &[MatcherToken<'a>], string: &'c str, position: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        match_sequence(tokens, position, &|token, position, next| self.match_token(token, string, position, next), next)
+    }
+
+    /// This calls `next` with each position the token could finish matching at, if it
+    /// starts at `position`, in the order they should be tried. It stops as soon as
+    /// `next` returns `true`, and returns whether that happened. If the options have
+    /// a `Trace`, it's told about each thing the token matched, or that it didn't.
+    /// Each call is a step, and once there have been too many, nothing matches.
+    fn match_token(&self, token: &MatcherToken<'a>, string: &'c str, position: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        self.steps.set(self.steps.get().saturating_add(1));
+        if self.exceeded_budget() {
+            return false;
+        }
+        if self.streaming && self.runs_out(token, &string[position..]) {
+            self.ran_out.set(true);
+        }
+
+        let Some(Trace(trace)) = self.options.trace else {
+            return self.try_token(token, string, position, next);
+        };
+
+        let mut matched = false;
+        let found = self.try_token(token, string, position, &mut |end| {
+            matched = true;
+            trace(token, position, Some(&string[position..end]));
+            next(end)
+        });
+        if !matched {
+            trace(token, position, None);
+        }
+        found
+    }
+
+    /// This is `match_token`, without the trace.
+    fn try_token(&self, token: &MatcherToken<'a>, string: &'c str, position: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        let unmatched = &string[position..];
+        match token {
+            MatcherToken::WildCard => match unmatched.chars().next() {
+                Some('\n') if !self.options.dot_matches_newline => false,
+                Some(c) if self.options.wildcard.is_some_and(|Wildcard(wildcard)| !wildcard(c)) => false,
+                Some(_) if self.options.dot_matches_grapheme => next(position + grapheme_len(unmatched)),
+                Some(c) => next(position + c.len_utf8()),
+                None => false,
+            },
+
+            // Options are tried in order, so if `(a|ab)c` can't match `c` after
+            // `a`, it goes back and tries `ab` instead.
+            MatcherToken::OneOfText(options) => options
+                .iter()
+                .any(|option| self.match_sequence(option, string, position, next)),
+            MatcherToken::LazyGroup(inside) => inside
+                .split('|')
+                .any(|option| self.starts_with(unmatched, option) && next(position + option.len())),
+
+            // What the group matched is recorded before going on to `next`, and
+            // forgotten again if `next` doesn't match, so it can try another way.
+            MatcherToken::NamedGroup { name, options } => options.iter().any(|option| {
+                self.match_sequence(option, string, position, &mut |end| {
+                    self.captures.borrow_mut().push((name, &string[position..end]));
+                    let matched = next(end);
+                    if !matched {
+                        self.captures.borrow_mut().pop();
+                    }
+                    matched
+                })
+            }),
+
+            // Case is only ignored inside the group, so it goes back to how it was
+            // for `next`, and is ignored again if `next` backtracks into the group.
+            MatcherToken::IgnoreCase(options) => {
+                let outside = self.ignore_case.replace(true);
+                let found = options.iter().any(|option| {
+                    self.match_sequence(option, string, position, &mut |end| {
+                        self.ignore_case.set(outside);
+                        let matched = next(end);
+                        self.ignore_case.set(true);
+                        matched
+                    })
+                });
+                self.ignore_case.set(outside);
+                found
+            }
+
+            MatcherToken::RawText(text) => self.starts_with(unmatched, text) && next(position + text.len()),
+
+            // The trie only finds exact bytes, so ignoring case means checking each word.
+            MatcherToken::OneOfWords(set) if self.ignore_case.get() => set
+                .words
+                .iter()
+                .any(|word| self.starts_with(unmatched, word) && next(position + word.len())),
+
+            MatcherToken::OneOfWords(set) => set
+                .prefixes_of(unmatched.as_bytes())
+                .into_iter()
+                .any(|(_, len)| next(position + len)),
+
+            MatcherToken::CharClass(set) => match unmatched.chars().next() {
+                Some(c) if self.ignore_case.get() && set.contains_ignoring_case(c) => next(position + c.len_utf8()),
+                Some(c) if !self.ignore_case.get() && set.contains(c) => next(position + c.len_utf8()),
+                _ => false,
+            },
+
+            // Matching the inner token is tried first, so this is greedy.
+            MatcherToken::Optional(inner) => self.match_token(inner, string, position, next) || next(position),
+
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                match_repeat((*min, *max, *greedy), 0, position, &|position, next| self.match_token(inner, string, position, next), next)
+            }
+
+            MatcherToken::StartAnchor => position == 0 && next(position),
+
+            MatcherToken::EndAnchor => unmatched.is_empty() && next(position),
+
+            MatcherToken::WordBoundary => is_word_boundary(string, position) && next(position),
+        }
+    }
+
+    /// This is the index of the option a group used to match `range` of the string.
+    /// Options are tried in order, so it's the first one which can match exactly
+    /// that range. Whatever came after the group matched after that range, so the
+    /// search would have used that option. Tokens which aren't groups give `None`.
+    fn choice(&self, token: &MatcherToken<'a>, string: &'c str, range: Range<usize>) -> Option<usize> {
+        let options: &[Vec<MatcherToken<'a>>] = match token {
+            MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => options,
+            MatcherToken::OneOfWords(set) => {
+                let unmatched = &string[range.start..];
+                return set.words.iter().position(|word| word.len() == range.len() && self.starts_with(unmatched, word));
+            }
+            MatcherToken::LazyGroup(inside) => {
+                let unmatched = &string[range.start..];
+                return inside.split('|').position(|option| option.len() == range.len() && self.starts_with(unmatched, option));
+            }
+            _ => return None,
+        };
+        let outside = self.ignore_case.replace(self.ignore_case.get() || matches!(token, MatcherToken::IgnoreCase(_)));
+        let choice = options
+            .iter()
+            .position(|option| self.match_sequence(option, string, range.start, &mut |end| end == range.end));
+        self.ignore_case.set(outside);
+        choice
+    }
+
+    /// This is whether `unmatched` starts with `text`, ignoring ASCII case if the
+    /// search is. If it does, `text.len()` is on a char boundary of `unmatched`:
+    /// both are valid UTF-8, and ASCII letters only swap with other ASCII letters.
+    /// That's also why the bytes can be compared straight away, without checking
+    /// for a char boundary first, which is most of the work for ASCII text.
+    fn starts_with(&self, unmatched: &str, text: &str) -> bool {
+        let (unmatched, text) = (unmatched.as_bytes(), text.as_bytes());
+        if self.ignore_case.get() {
+            unmatched.get(..text.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(text))
+        } else {
+            unmatched.starts_with(text)
+        }
+    }
+}
+
+/// This is what matching calls with each position a token could end at. When it
+/// returns `true`, the whole search is done.
+type Next<'n> = dyn FnMut(usize) -> bool + 'n;
+
+/// This calls `next` with the position after each of the tokens has matched, one
+/// after another, starting at `position`. `step` matches one token, calling `next`
+/// with each position it could end at. It stops as soon as `next` returns `true`.
+/// Both `Search` and `BytesToken` match lists of tokens with this.
+fn match_sequence<T>(
+    tokens: &[T],
+    position: usize,
+    step: &dyn Fn(&T, usize, &mut Next) -> bool,
+    next: &mut Next,
+) -> bool {
+    match tokens.split_first() {
+        None => next(position),
+        Some((token, rest)) => step(token, position, &mut |end| match_sequence(rest, end, step, next)),
+    }
+}
+
+/// This matches a repeat which has already matched `count` times, up to
+/// `position`, where `step` matches what's repeated once more. If it's greedy,
+/// it tries one more repetition before stopping, so it matches as many as it
+/// can. Otherwise it tries stopping first, so it matches as few as it can.
+fn match_repeat(
+    (min, max, greedy): (usize, Option<usize>, bool),
+    count: usize,
+    position: usize,
+    step: &dyn Fn(usize, &mut Next) -> bool,
+    next: &mut Next,
+) -> bool {
+    let can_repeat = max.is_none_or(|max| count < max);
+    let repeat = |next: &mut Next| {
+        // Repeating something that matched nothing would loop forever,
+        // unless we still need more repetitions to reach `min`.
+        can_repeat
+            && step(position, &mut |end| {
+                (end > position || count < min) && match_repeat((min, max, greedy), count + 1, end, step, next)
+            })
+    };
+
+    if greedy && repeat(next) {
+        return true;
+    }
+    if count >= min && next(position) {
+        return true;
+    }
+    !greedy && repeat(next)
+}
+
+/// This is why a pattern couldn't be parsed. Each reason
+/// has the byte offset in the pattern where it went wrong.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MatcherParseError {
+    /// A `(` was never closed, or a `)` was never opened.
+    UnbalancedParen { at: usize },
+    /// A `[` was never closed.
+    UnclosedClass { at: usize },
+    /// The character class has a range like `z-a`.
+    BackwardsRange { at: usize },
+    /// The pattern ended with a `\` that has nothing to escape.
+    TrailingBackslash { at: usize },
+    /// A `\` came before something that can't be escaped.
+    UnknownEscape { at: usize },
+    /// A `?`, `*`, `+` or `{...}` had no token before it.
+    NothingToRepeat { at: usize },
+    /// A `{...}` wasn't closed, or didn't have sensible numbers in it.
+    InvalidRepetition { at: usize },
+    /// A `(?<name>...)` group had no `>`, or its name was empty or had
+    /// something other than letters, digits and `_` in it.
+    InvalidGroupName { at: usize },
+    /// One of the options separated by `|` was empty, like in `(a||b)`.
+    EmptyAlternative { at: usize },
+    /// A group had nothing in it, like `()`.
+    EmptyGroup { at: usize },
+    /// A `\p{...}` wasn't closed, or had a name it doesn't know.
+    UnknownProperty { at: usize },
+    /// A pattern for a `BytesMatcher` wasn't valid UTF-8 from this byte on.
+    InvalidUtf8 { at: usize },
+    /// A group had more options than the limit given when parsing it. This
+    /// is the byte offset of the `|` before the first option over the limit.
+    TooManyAlternatives { at: usize },
+}
+
+impl fmt::Display for MatcherParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatcherParseError::UnbalancedParen { at } => write!(f, "unbalanced parenthesis at byte {at}"),
+            MatcherParseError::UnclosedClass { at } => write!(f, "character class at byte {at} is never closed"),
+            MatcherParseError::BackwardsRange { at } => {
+                write!(f, "character class at byte {at} has a range which goes backwards")
+            }
+            MatcherParseError::TrailingBackslash { at } => write!(f, "nothing to escape after `\\` at byte {at}"),
+            MatcherParseError::UnknownEscape { at } => write!(f, "unknown escape at byte {at}"),
+            MatcherParseError::NothingToRepeat { at } => write!(f, "nothing to repeat before byte {at}"),
+            MatcherParseError::InvalidRepetition { at } => write!(f, "invalid repetition count at byte {at}"),
+            MatcherParseError::InvalidGroupName { at } => write!(f, "invalid group name at byte {at}"),
+            MatcherParseError::EmptyAlternative { at } => write!(f, "empty alternative at byte {at}"),
+            MatcherParseError::EmptyGroup { at } => write!(f, "empty group at byte {at}"),
+            MatcherParseError::UnknownProperty { at } => write!(f, "unknown Unicode property at byte {at}"),
+            MatcherParseError::InvalidUtf8 { at } => write!(f, "pattern isn't UTF-8 at byte {at}"),
+            MatcherParseError::TooManyAlternatives { at } => write!(f, "too many alternatives at byte {at}"),
+        }
+    }
+}
+
+impl core::error::Error for MatcherParseError {}
+
+impl MatcherParseError {
+    /// This is the byte offset in the pattern where it went wrong.
+    pub fn at(&self) -> usize {
+        match *self {
+            MatcherParseError::UnbalancedParen { at }
+            | MatcherParseError::UnclosedClass { at }
+            | MatcherParseError::BackwardsRange { at }
+            | MatcherParseError::TrailingBackslash { at }
+            | MatcherParseError::UnknownEscape { at }
+            | MatcherParseError::NothingToRepeat { at }
+            | MatcherParseError::InvalidRepetition { at }
+            | MatcherParseError::InvalidGroupName { at }
+            | MatcherParseError::EmptyAlternative { at }
+            | MatcherParseError::EmptyGroup { at }
+            | MatcherParseError::UnknownProperty { at }
+            | MatcherParseError::InvalidUtf8 { at }
+            | MatcherParseError::TooManyAlternatives { at } => at,
+        }
+    }
+}
+
+/// This writes `error` out like a compiler would, with the line of `pattern` it's
+/// in and a `^` under the character where it went wrong. The `^` is put in the
+/// column of that character, counting each character before it as one column.
+pub fn render_error(pattern: &str, error: &MatcherParseError) -> String {
+    let mut at = error.at().min(pattern.len());
+    while !pattern.is_char_boundary(at) {
+        at -= 1;
+    }
+    let line_start = pattern[..at].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = pattern[at..].find('\n').map_or(pattern.len(), |newline| at + newline);
+    let column = pattern[line_start..at].chars().count();
+
+    format!("error: {error}\n  |\n  | {}\n  | {}^\n", &pattern[line_start..line_end], " ".repeat(column))
+}
+
+/// This is the reason a replacement couldn't be used. Each variant has the
+/// byte offset of the `$` in the replacement which was wrong.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplacementError {
+    /// A `$` wasn't followed by `$`, `0` or a group name.
+    MissingGroupName { at: usize },
+    /// A `$name` used a name which isn't the name of any group in the pattern.
+    UnknownGroup { at: usize },
+}
+
+impl fmt::Display for ReplacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplacementError::MissingGroupName { at } => write!(f, "no group name after `$` at byte {at}"),
+            ReplacementError::UnknownGroup { at } => write!(f, "no group in the pattern has the name at byte {at}"),
+        }
+    }
+}
+
+impl core::error::Error for ReplacementError {}
+
+/// This is why `Matcher::match_string_budgeted` gave up: trying the tokens
+/// took more than `max_steps` steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    max_steps: usize,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gave up matching after {} steps", self.max_steps)
+    }
+}
+
+impl core::error::Error for BudgetExceeded {}
+
+/// This is a pattern which owns its text, so it can be stored without
+/// borrowing anything. A `Matcher`'s tokens point into its text, and a
+/// struct can't point into its own `String`, so this only keeps the text.
+/// It's checked when it's created, and parsed again by `matcher`.
+/// With the `serde` feature, it is serialized as just its text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MatcherOwned {
+    text: String,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for MatcherOwned {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MatcherOwned {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl MatcherOwned {
+    /// This gives a `Matcher` which borrows this pattern. Each one
+    /// starts again from `most_tokens_matched` being 0.
+    pub fn matcher(&self) -> Matcher<'_> {
+        Matcher::new(&self.text).expect("the pattern was checked when it was parsed")
+    }
+}
+
+impl FromStr for MatcherOwned {
+    type Err = MatcherParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Matcher::new(text)?;
+        Ok(MatcherOwned { text: text.to_string() })
+    }
+}
+
+/// This keeps copies of patterns for `Matcher::new_in`, until it's dropped.
+/// Each copy is in its own box, so when the list of them grows, the boxes
+/// move but the text in them doesn't, and nothing which has been lent out moves.
+#[derive(Debug, Default)]
+pub struct Arena {
+    texts: RefCell<Vec<Box<str>>>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena::default()
+    }
+
+    /// This copies `text` into the arena, and lends the copy out for as long as
+    /// the arena is borrowed.
+    pub fn alloc(&self, text: &str) -> &str {
+        let mut texts = self.texts.borrow_mut();
+        texts.push(text.into());
+        let copy: *const str = &**texts.last().unwrap();
+        // SAFETY: The copy is on the heap, so it stays where it is when `texts`
+        // grows. Boxes are never taken out of `texts`, so it lives until the arena
+        // is dropped, which can't happen while `&self` is borrowed.
+        unsafe { &*copy }
+    }
+}
+
+/// This is a `Matcher` compiled into tokens which own their text, from
+/// `Matcher::compile`. Unlike a `Matcher` it doesn't borrow the pattern, and
+/// unlike `MatcherOwned` it doesn't parse the pattern again. To match, it lends
+/// out `MatcherToken`s borrowing from its own tokens, along with the trie of
+/// any big group of words, which is only made once, when it's compiled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    tokens: Vec<ProgramToken>,
+    options: MatchOptions,
+}
+
+/// This is a `MatcherToken` which owns its text. See `MatcherToken` for what each one matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProgramToken {
+    RawText(Box<str>),
+    OneOfText(Vec<Vec<ProgramToken>>),
+    NamedGroup { name: Box<str>, options: Vec<Vec<ProgramToken>> },
+    IgnoreCase(Vec<Vec<ProgramToken>>),
+    WildCard,
+    CharClass(CharSet),
+    Optional(Box<ProgramToken>),
+    Repeat { inner: Box<ProgramToken>, min: usize, max: Option<usize>, greedy: bool },
+    OneOfWords { words: Vec<Box<str>>, nodes: Vec<TrieNode> },
+    StartAnchor,
+    EndAnchor,
+    WordBoundary,
+}
+
+impl ProgramToken {
+    fn compile(token: &MatcherToken) -> ProgramToken {
+        let compile_options = |options: &[Vec<MatcherToken>]| {
+            options.iter().map(|option| option.iter().map(ProgramToken::compile).collect()).collect()
+        };
+
+        match token {
+            MatcherToken::RawText(text) => ProgramToken::RawText((**text).into()),
+            MatcherToken::OneOfText(options) => ProgramToken::OneOfText(compile_options(options)),
+            MatcherToken::LazyGroup(inside) => {
+                ProgramToken::OneOfText(inside.split('|').map(|option| vec![ProgramToken::RawText(option.into())]).collect())
+            }
+            MatcherToken::NamedGroup { name, options } => {
+                ProgramToken::NamedGroup { name: (*name).into(), options: compile_options(options) }
+            }
+            MatcherToken::IgnoreCase(options) => ProgramToken::IgnoreCase(compile_options(options)),
+            MatcherToken::WildCard => ProgramToken::WildCard,
+            MatcherToken::CharClass(set) => ProgramToken::CharClass(set.clone()),
+            MatcherToken::Optional(inner) => ProgramToken::Optional(Box::new(ProgramToken::compile(inner))),
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                ProgramToken::Repeat { inner: Box::new(ProgramToken::compile(inner)), min: *min, max: *max, greedy: *greedy }
+            }
+            MatcherToken::OneOfWords(set) => ProgramToken::OneOfWords {
+                words: set.words.iter().map(|&word| word.into()).collect(),
+                nodes: set.nodes.to_vec(),
+            },
+            MatcherToken::StartAnchor => ProgramToken::StartAnchor,
+            MatcherToken::EndAnchor => ProgramToken::EndAnchor,
+            MatcherToken::WordBoundary => ProgramToken::WordBoundary,
+        }
+    }
+
+    /// This is the `MatcherToken` this was compiled from, borrowing the text from here.
+    #[require_lifetimes]
+    fn as_token<'p>(&'p self) -> MatcherToken<'p> {
+        let as_options = |options: &'p Vec<Vec<ProgramToken>>| {
+            options.iter().map(|option| option.iter().map(ProgramToken::as_token).collect()).collect()
+        };
+
+        match self {
+            ProgramToken::RawText(text) => MatcherToken::RawText(Cow::Borrowed(text)),
+            ProgramToken::OneOfText(options) => MatcherToken::OneOfText(as_options(options)),
+            ProgramToken::NamedGroup { name, options } => MatcherToken::NamedGroup { name, options: as_options(options) },
+            ProgramToken::IgnoreCase(options) => MatcherToken::IgnoreCase(as_options(options)),
+            ProgramToken::WildCard => MatcherToken::WildCard,
+            ProgramToken::CharClass(set) => MatcherToken::CharClass(set.clone()),
+            ProgramToken::Optional(inner) => MatcherToken::Optional(Box::new(inner.as_token())),
+            ProgramToken::Repeat { inner, min, max, greedy } => {
+                MatcherToken::Repeat { inner: Box::new(inner.as_token()), min: *min, max: *max, greedy: *greedy }
+            }
+            ProgramToken::OneOfWords { words, nodes } => {
+                MatcherToken::OneOfWords(WordSet { words: words.iter().map(|word| &**word).collect(), nodes: Cow::Borrowed(nodes) })
+            }
+            ProgramToken::StartAnchor => MatcherToken::StartAnchor,
+            ProgramToken::EndAnchor => MatcherToken::EndAnchor,
+            ProgramToken::WordBoundary => MatcherToken::WordBoundary,
+        }
+    }
+}
+
+impl Program {
+    /// This lends out the tokens, for as long as the program is borrowed. They're
+    /// made again for each match, but their text and tries are only borrowed.
+    fn tokens(&self) -> Vec<MatcherToken<'_>> {
+        self.tokens.iter().map(ProgramToken::as_token).collect()
+    }
+
+    /// This is `Matcher::matched_parts`: the parts of the string each token matched.
+    /// It doesn't keep track of how far it got, so it only needs `&self`.
+    pub fn match_string<'c>(&self, string: &'c str) -> Vec<&'c str> {
+        let tokens = self.tokens();
+        let attempt = Search::new(self.options).match_tokens(&tokens, string, 0, false);
+        attempt.substrings(string).into_iter().map(|(_, part)| part).collect()
+    }
+
+    /// This is `Matcher::is_match`.
+    pub fn is_match(&self, string: &str) -> bool {
+        Search::new(self.options).match_tokens(&self.tokens(), string, 0, true).complete
+    }
+
+    /// This is `Matcher::find`. Like it, it only tries the start of the haystack
+    /// if the pattern starts with `^`.
+    pub fn find(&self, haystack: &str) -> Option<(usize, usize)> {
+        let tokens = self.tokens();
+        let last_start = if matches!(tokens.first(), Some(MatcherToken::StartAnchor)) { 0 } else { haystack.len() };
+        let mut starts = haystack
+            .char_indices()
+            .map(|(start, _)| start)
+            .chain(core::iter::once(haystack.len()))
+            .take_while(|&start| start <= last_start);
+
+        starts.find_map(|start| {
+            let attempt = Search::new(self.options).match_tokens(&tokens, haystack, start, false);
+            let found = (start, attempt.end);
+            (attempt.complete && self.options.keeps_match(haystack, found)).then_some(found)
+        })
+    }
+}
+
+/// This is a matcher for bytes which might not be UTF-8, from `BytesMatcher::new`.
+/// The pattern is written and parsed the same way as for `Matcher`, but each
+/// token matches bytes: text matches its UTF-8 bytes, a `.` matches any one byte,
+/// and a character class matches one byte, taking its value as the character,
+/// so classes are meant for ASCII. It doesn't have any `MatchOptions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytesMatcher<'a> {
+    tokens: Vec<BytesToken<'a>>,
+}
+
+/// This is a `MatcherToken` for matching bytes. Named groups are just groups here,
+/// since there's no way to ask what they matched, and so are `(?i:...)` groups,
+/// since bytes are always matched exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytesToken<'a> {
+    Bytes(Cow<'a, [u8]>),
+    OneOf(Vec<Vec<BytesToken<'a>>>),
+    OneOfWords(WordSet<'a>),
+    AnyByte,
+    Class(CharSet),
+    Optional(Box<BytesToken<'a>>),
+    Repeat { inner: Box<BytesToken<'a>>, min: usize, max: Option<usize>, greedy: bool },
+    StartAnchor,
+    EndAnchor,
+    WordBoundary,
+}
+
+impl<'a> BytesToken<'a> {
+    fn from_token(token: &MatcherToken<'a>) -> BytesToken<'a> {
+        let from_options = |options: &[Vec<MatcherToken<'a>>]| {
+            options.iter().map(|option| option.iter().map(BytesToken::from_token).collect()).collect()
+        };
+
+        match token {
+            MatcherToken::RawText(Cow::Borrowed(text)) => BytesToken::Bytes(Cow::Borrowed(text.as_bytes())),
+            MatcherToken::RawText(Cow::Owned(text)) => BytesToken::Bytes(Cow::Owned(text.clone().into_bytes())),
+            MatcherToken::OneOfText(options) | MatcherToken::NamedGroup { options, .. } | MatcherToken::IgnoreCase(options) => {
+                BytesToken::OneOf(from_options(options))
+            }
+            MatcherToken::LazyGroup(inside) => {
+                BytesToken::OneOf(inside.split('|').map(|option| vec![BytesToken::Bytes(Cow::Borrowed(option.as_bytes()))]).collect())
+            }
+            MatcherToken::OneOfWords(set) => BytesToken::OneOfWords(set.clone()),
+            MatcherToken::WildCard => BytesToken::AnyByte,
+            MatcherToken::CharClass(set) => BytesToken::Class(set.clone()),
+            MatcherToken::Optional(inner) => BytesToken::Optional(Box::new(BytesToken::from_token(inner))),
+            MatcherToken::Repeat { inner, min, max, greedy } => {
+                BytesToken::Repeat { inner: Box::new(BytesToken::from_token(inner)), min: *min, max: *max, greedy: *greedy }
+            }
+            MatcherToken::StartAnchor => BytesToken::StartAnchor,
+            MatcherToken::EndAnchor => BytesToken::EndAnchor,
+            MatcherToken::WordBoundary => BytesToken::WordBoundary,
+        }
+    }
+
+    /// This is `Search::match_sequence`, for bytes.
+    fn match_sequence(tokens: &[BytesToken], bytes: &[u8], position: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        match_sequence(tokens, position, &|token: &BytesToken, position, next| token.match_at(bytes, position, next), next)
+    }
+
+    /// This is `Search::match_token`, for bytes.
+    fn match_at(&self, bytes: &[u8], position: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        let unmatched = &bytes[position..];
+        let is_word = |byte: Option<&u8>| byte.is_some_and(|byte| byte.is_ascii_alphanumeric() || *byte == b'_');
+
+        match self {
+            BytesToken::Bytes(text) => unmatched.starts_with(text) && next(position + text.len()),
+            BytesToken::OneOf(options) => options.iter().any(|option| Self::match_sequence(option, bytes, position, next)),
+            BytesToken::OneOfWords(set) => set.prefixes_of(unmatched).into_iter().any(|(_, len)| next(position + len)),
+            BytesToken::AnyByte => !unmatched.is_empty() && next(position + 1),
+            BytesToken::Class(set) => unmatched.first().is_some_and(|&byte| set.contains(byte.into())) && next(position + 1),
+            BytesToken::Optional(inner) => inner.match_at(bytes, position, next) || next(position),
+            BytesToken::Repeat { inner, min, max, greedy } => {
+                match_repeat((*min, *max, *greedy), 0, position, &|position, next| inner.match_at(bytes, position, next), next)
+            }
+            BytesToken::StartAnchor => position == 0 && next(position),
+            BytesToken::EndAnchor => unmatched.is_empty() && next(position),
+            BytesToken::WordBoundary => {
+                let before = position.checked_sub(1).and_then(|before| bytes.get(before));
+                is_word(before) != is_word(unmatched.first()) && next(position)
+            }
+        }
+    }
+}
+
+impl<'a> BytesMatcher<'a> {
+    /// This parses the pattern like `Matcher::new` does, so it has to be UTF-8,
+    /// even though the bytes it matches don't.
+    #[require_lifetimes]
+    pub fn new(pattern: &'a [u8]) -> Result<BytesMatcher<'a>, MatcherParseError> {
+        let text = core::str::from_utf8(pattern).map_err(|error| MatcherParseError::InvalidUtf8 { at: error.valid_up_to() })?;
+        let matcher = Matcher::new(text)?;
+        Ok(BytesMatcher { tokens: matcher.tokens.iter().map(BytesToken::from_token).collect() })
+    }
+
+    /// This is `match_string` for bytes, returning what each token matched at the
+    /// start of `bytes`. Unlike `match_string`, it returns `None` unless all of
+    /// the tokens matched.
+    #[require_lifetimes]
+    pub fn match_bytes<'b, 'c>(&'b self, bytes: &'c [u8]) -> Option<Vec<(&'b BytesToken<'a>, &'c [u8])>> {
+        let mut ends = Vec::with_capacity(self.tokens.len());
+        if !Self::match_recording(&self.tokens, bytes, 0, &mut ends) {
+            return None;
+        }
+
+        let starts = core::iter::once(0).chain(ends.iter().copied());
+        let ranges = starts.zip(ends.iter().copied());
+        Some(self.tokens.iter().zip(ranges).map(|(token, (start, end))| (token, &bytes[start..end])).collect())
+    }
+
+    /// This matches the tokens one after another from `position`, pushing where
+    /// each one ended onto `ends`. If they can't all match, `ends` is left as it was.
+    fn match_recording(tokens: &[BytesToken], bytes: &[u8], position: usize, ends: &mut Vec<usize>) -> bool {
+        let Some((token, rest)) = tokens.split_first() else {
+            return true;
+        };
+
+        token.match_at(bytes, position, &mut |end| {
+            ends.push(end);
+            let matched = Self::match_recording(rest, bytes, end, ends);
+            if !matched {
+                ends.pop();
+            }
+            matched
+        })
+    }
+
+    /// This is `Matcher::is_match` for bytes, so the tokens have to use up all of them.
+    pub fn is_match(&self, bytes: &[u8]) -> bool {
+        BytesToken::match_sequence(&self.tokens, bytes, 0, &mut |end| end == bytes.len())
+    }
+
+    /// This is `Matcher::find` for bytes. Any byte offset can be a start.
+    pub fn find(&self, haystack: &[u8]) -> Option<(usize, usize)> {
+        (0..=haystack.len()).find_map(|start| {
+            let mut found = None;
+            BytesToken::match_sequence(&self.tokens, haystack, start, &mut |end| {
+                found = Some((start, end));
+                true
+            });
+            found
+        })
+    }
+}
+
+/// This is a `Matcher` which only matches a whole string, like most regex
+/// libraries do, rather than matching the start of it like `Matcher` does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullMatcher<'a> {
+    matcher: Matcher<'a>,
+}
+
+impl<'a> FullMatcher<'a> {
+    #[require_lifetimes]
+    pub fn new(text: &'a str) -> Result<FullMatcher<'a>, MatcherParseError> {
+        Ok(FullMatcher { matcher: Matcher::new(text)? })
+    }
+
+    /// This returns what each token matched, but only if they used
+    /// up the whole of the string. Otherwise it returns `None`.
+    #[require_lifetimes]
+    pub fn match_string<'b, 'c>(&'b mut self, string: &'c str) -> Option<Vec<(&'b MatcherToken<'a>, &'c str)>> {
+        self.matcher.full_match(string)
+    }
+
+    pub fn is_match(&mut self, string: &str) -> bool {
+        self.matcher.is_match(string)
+    }
+}
+
+impl<'a> From<Matcher<'a>> for FullMatcher<'a> {
+    fn from(matcher: Matcher<'a>) -> FullMatcher<'a> {
+        FullMatcher { matcher }
+    }
+}
+
+/// This is several matchers, to find which of them matches the start of a
+/// string, like a lexer finding which kind of token comes next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiMatcher<'a> {
+    matchers: Vec<Matcher<'a>>,
+}
+
+impl<'a> MultiMatcher<'a> {
+    /// This returns the index of the first pattern which matches the start of the
+    /// string, and how many bytes it matched. Later patterns aren't tried once one
+    /// matches, so a keyword pattern should come before a more general one.
+    pub fn match_any(&mut self, string: &str) -> Option<(usize, usize)> {
+        self.matchers
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, matcher)| Some((index, matcher.match_len(string)?)))
+    }
+
+    /// This is like `match_any`, but it tries every pattern and returns the one
+    /// which matched the most bytes. If several match the most, the first wins.
+    pub fn match_longest(&mut self, string: &str) -> Option<(usize, usize)> {
+        let mut longest: Option<(usize, usize)> = None;
+        for (index, matcher) in self.matchers.iter_mut().enumerate() {
+            match matcher.match_len(string) {
+                Some(len) if longest.is_none_or(|(_, longest)| len > longest) => longest = Some((index, len)),
+                _ => {}
+            }
+        }
+        longest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::borrow::Cow;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use super::{
+        Alternative, Arena, BudgetExceeded, BytesMatcher, BytesToken, CharSet, FullMatcher, MatchOptions, Matcher, MatcherBuilder, MatcherOwned, MatcherParseError, MatcherToken,
+        Progress, ReplacementError, Search, render_error, trace_to_stderr,
+    };
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// This counts how many allocations each thread makes, so a test can check
+    /// how much something allocates without the other tests getting in the way.
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// This runs `f`, and returns what it returned along with how many
+    /// allocations it made.
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (result, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    /// This builds the `OneOfText` for a group of plain strings, like `(d|e|f)`.
+    fn one_of<'a>(options: &[&'a str]) -> MatcherToken<'a> {
+        MatcherToken::OneOfText(options.iter().map(|option| vec![MatcherToken::RawText(Cow::Borrowed(option))]).collect())
+    }
+
+    #[test]
+    fn simple_test() {
+        let match_string = "abc(d|e|f).".to_string();
+        let mut matcher = Matcher::new(&match_string).unwrap();
+
+        assert_eq!(matcher.progress.most_tokens_matched, 0);
+
+        {
+            let candidate1 = "abcge".to_string();
+            let result = matcher.match_string(&candidate1);
+            assert_eq!(result, vec![(&MatcherToken::RawText(Cow::Borrowed("abc")), "abc"),]);
+            assert_eq!(matcher.progress.most_tokens_matched, 1);
+        }
+
+        {
+            // Change 'e' to '💪' if you want to test unicode.
+            let candidate1 = "abcd💪".to_string();
+            let result = matcher.match_string(&candidate1);
+            assert_eq!(
+                result,
+                vec![
+                    (&MatcherToken::RawText(Cow::Borrowed("abc")), "abc"),
+                    (&one_of(&["d", "e", "f"]), "d"),
+                    (&MatcherToken::WildCard, "💪") // or '💪'
+                ]
+            );
+            assert_eq!(matcher.progress.most_tokens_matched, 3);
+        }
+    }
+
+    #[test]
+    fn broken_matcher() {
+        let match_string = "abc(d|e|f.".to_string();
+        let matcher = Matcher::new(&match_string);
+        assert_eq!(matcher, Err(MatcherParseError::UnbalancedParen { at: 3 }));
+    }
+
+    #[test]
+    fn is_match_test() {
+        let match_string = "abc(d|e|f).".to_string();
+        let mut matcher = Matcher::new(&match_string).unwrap();
+
+        assert!(matcher.is_match("abcdx"));
+        assert!(matcher.is_match("abcf💪"));
+        assert!(!matcher.is_match("abc"));
+        assert!(!matcher.is_match("abcdxy"));
+        assert!(!matcher.is_match(""));
+
+        let mut empty = Matcher::new("").unwrap();
+        assert!(empty.is_match(""));
+        assert!(!empty.is_match("a"));
+    }
+
+    #[test]
+    fn optional_test() {
+        let mut matcher = Matcher::new("ab?c").unwrap();
+        assert_eq!(
+            matcher.tokens,
+            vec![
+                MatcherToken::RawText(Cow::Borrowed("a")),
+                MatcherToken::Optional(Box::new(MatcherToken::RawText(Cow::Borrowed("b")))),
+                MatcherToken::RawText(Cow::Borrowed("c")),
+            ]
+        );
+
+        assert!(matcher.is_match("ac"));
+        assert!(matcher.is_match("abc"));
+        assert!(!matcher.is_match("abbc"));
+        assert_eq!(
+            matcher.match_string("ac"),
+            vec![(&MatcherToken::RawText(Cow::Borrowed("a")), "a"), (&MatcherToken::RawText(Cow::Borrowed("c")), "c")]
+        );
+
+        let mut wildcard = Matcher::new("a.?").unwrap();
+        assert!(wildcard.is_match("a"));
+        assert!(wildcard.is_match("a💪"));
+        assert!(!wildcard.is_match("a💪b"));
+
+        assert_eq!(Matcher::new("?a"), Err(MatcherParseError::NothingToRepeat { at: 0 }));
+    }
+
+    #[test]
+    fn star_test() {
+        let mut matcher = Matcher::new("ba*").unwrap();
+        let star = MatcherToken::Repeat { inner: Box::new(MatcherToken::RawText(Cow::Borrowed("a"))), min: 0, max: None, greedy: true };
+        assert_eq!(matcher.tokens, vec![MatcherToken::RawText(Cow::Borrowed("b")), star]);
+
+        assert!(matcher.is_match("b"));
+        assert!(matcher.is_match("baaa"));
+        assert!(!matcher.is_match("baab"));
+
+        let mut any = Matcher::new("x.*").unwrap();
+        assert_eq!(
+            any.match_string("x💪yz"),
+            vec![
+                (&MatcherToken::RawText(Cow::Borrowed("x")), "x"),
+                (&MatcherToken::Repeat { inner: Box::new(MatcherToken::WildCard), min: 0, max: None, greedy: true }, "💪yz"),
+            ]
+        );
+
+        let mut empty = Matcher::new("a*").unwrap();
+        assert_eq!(empty.match_string(""), vec![]);
+        assert!(empty.is_match(""));
+
+        assert_eq!(Matcher::new("*a"), Err(MatcherParseError::NothingToRepeat { at: 0 }));
+    }
+
+    #[test]
+    fn plus_test() {
+        let mut matcher = Matcher::new("a+").unwrap();
+        assert!(matcher.is_match("a"));
+        assert!(matcher.is_match("aaaa"));
+        assert!(!matcher.is_match(""));
+        assert!(!matcher.is_match("bbb"));
+        assert_eq!(matcher.match_string("bbb"), vec![]);
+
+        let mut animals = Matcher::new("(cat|dog)+").unwrap();
+        let plus = MatcherToken::Repeat {
+            inner: Box::new(one_of(&["cat", "dog"])),
+            min: 1,
+            max: None,
+            greedy: true,
+        };
+        assert_eq!(animals.match_string("catdogcat!"), vec![(&plus, "catdogcat")]);
+        assert!(animals.is_match("catdogcat"));
+        assert!(!animals.is_match("cow"));
+
+        assert_eq!(Matcher::new("+a"), Err(MatcherParseError::NothingToRepeat { at: 0 }));
+    }
+
+    #[test]
+    fn group_repeat_test() {
+        let mut matcher = Matcher::new("(ab)+c").unwrap();
+        let group = MatcherToken::Repeat { inner: Box::new(one_of(&["ab"])), min: 1, max: None, greedy: true };
+        assert_eq!(matcher.tokens, vec![group.clone(), MatcherToken::RawText(Cow::Borrowed("c"))]);
+        assert_eq!(matcher.match_string("ababc"), vec![(&group, "abab"), (&MatcherToken::RawText(Cow::Borrowed("c")), "c")]);
+        assert!(matcher.is_match("ababc"));
+        assert!(matcher.is_match("abc"));
+        assert!(!matcher.is_match("aba"));
+        assert!(!matcher.is_match("c"));
+        assert!(!matcher.is_match("abac"));
+
+        // A repeat of a group gives back whole repeats, and can pick a different
+        // option each time, to let what comes after it match.
+        let mut backtrack = Matcher::new("(ab|a)*ab").unwrap();
+        assert!(backtrack.is_match("aabab"));
+        assert!(backtrack.is_match("ab"));
+        assert!(Matcher::new("(a.){2}").unwrap().is_match("a1a2"));
+        assert!(!Matcher::new("(a.){2}").unwrap().is_match("a1b2"));
+    }
+
+    #[test]
+    fn lazy_repeat_test() {
+        let mut greedy = Matcher::new("<.*>").unwrap();
+        let mut lazy = Matcher::new("<.*?>").unwrap();
+        assert_eq!(greedy.find("<a><b>"), Some((0, 6)));
+        assert_eq!(lazy.find("<a><b>"), Some((0, 3)));
+        assert_eq!(lazy.find_all("<a><b>"), vec![(0, 3), (3, 6)]);
+
+        // A lazy repeat still matches more if that's the only way to finish.
+        assert!(lazy.is_match("<a><b>"));
+
+        let mut plus = Matcher::new("a+?").unwrap();
+        assert_eq!(
+            plus.tokens,
+            vec![MatcherToken::Repeat { inner: Box::new(MatcherToken::RawText(Cow::Borrowed("a"))), min: 1, max: None, greedy: false }]
+        );
+        assert_eq!(plus.find("aaa"), Some((0, 1)));
+        assert_eq!(Matcher::new("a{2,}?").unwrap().find("aaaa"), Some((0, 2)));
+        assert_eq!(plus.to_string(), "a+?");
+    }
+
+    #[test]
+    fn bounded_repeat_test() {
+        let mut matcher = Matcher::new("a{2,4}").unwrap();
+        assert!(!matcher.is_match("a"));
+        assert!(matcher.is_match("aa"));
+        assert!(matcher.is_match("aaaa"));
+        assert!(!matcher.is_match("aaaaa"));
+
+        let mut exact = Matcher::new("ba{3}").unwrap();
+        assert_eq!(
+            exact.tokens[1],
+            MatcherToken::Repeat { inner: Box::new(MatcherToken::RawText(Cow::Borrowed("a"))), min: 3, max: Some(3), greedy: true }
+        );
+        assert!(exact.is_match("baaa"));
+        assert!(!exact.is_match("baa"));
+
+        let mut open = Matcher::new(".{2,}").unwrap();
+        assert!(!open.is_match("x"));
+        assert!(open.is_match("xyz"));
+
+        assert_eq!(Matcher::new("a{2,1}"), Err(MatcherParseError::InvalidRepetition { at: 1 }));
+        assert_eq!(Matcher::new("a{}"), Err(MatcherParseError::InvalidRepetition { at: 1 }));
+        assert_eq!(Matcher::new("a{x}"), Err(MatcherParseError::InvalidRepetition { at: 1 }));
+        assert_eq!(Matcher::new("a{2"), Err(MatcherParseError::InvalidRepetition { at: 1 }));
+        assert_eq!(Matcher::new("{2}"), Err(MatcherParseError::NothingToRepeat { at: 0 }));
+    }
+
+    #[test]
+    fn char_class_test() {
+        let mut matcher = Matcher::new("x[ab💪]").unwrap();
+        let class = MatcherToken::CharClass(CharSet::from_ranges(vec![('a', 'b'), ('💪', '💪')]));
+        assert_eq!(matcher.tokens[1], class);
+
+        assert!(matcher.is_match("xa"));
+        assert!(matcher.is_match("x💪"));
+        assert!(!matcher.is_match("xc"));
+        assert!(!matcher.is_match("x"));
+        assert_eq!(
+            matcher.match_string("x💪b"),
+            vec![(&MatcherToken::RawText(Cow::Borrowed("x")), "x"), (&class, "💪")]
+        );
+
+        let mut repeated = Matcher::new("[01]+").unwrap();
+        assert!(repeated.is_match("0110"));
+
+        let mut empty = Matcher::new("[]").unwrap();
+        assert!(!empty.is_match("a"));
+        assert!(!empty.is_match(""));
+
+        assert_eq!(Matcher::new("[abc"), Err(MatcherParseError::UnclosedClass { at: 0 }));
+    }
+
+    #[test]
+    fn char_range_test() {
+        let mut matcher = Matcher::new("[a-z0-9_]+").unwrap();
+        assert!(matcher.is_match("snake_case_42"));
+        assert!(!matcher.is_match("CamelCase"));
+
+        let mut dash = Matcher::new("[-a-c]").unwrap();
+        assert!(dash.is_match("-"));
+        assert!(dash.is_match("b"));
+        assert!(!dash.is_match("d"));
+
+        let set = CharSet::from_ranges(vec![('x', 'z'), ('a', 'f'), ('c', 'k'), ('l', 'l')]);
+        assert_eq!(set.ranges, vec![('a', 'l'), ('x', 'z')]);
+        assert!(set.contains('a'));
+        assert!(set.contains('l'));
+        assert!(!set.contains('m'));
+        assert!(set.contains('y'));
+
+        assert_eq!(Matcher::new("x[z-a]"), Err(MatcherParseError::BackwardsRange { at: 1 }));
+    }
+
+    #[test]
+    fn negated_char_class_test() {
+        let mut matcher = Matcher::new("[^0-9]").unwrap();
+        assert!(matcher.is_match("a"));
+        assert!(matcher.is_match("💪"));
+        assert!(!matcher.is_match("5"));
+        assert!(!matcher.is_match(""));
+        assert_eq!(matcher.match_string("ab")[0].1, "a");
+
+        let mut any = Matcher::new("[^]").unwrap();
+        assert!(any.is_match("x"));
+        assert!(any.is_match("]"));
+        assert!(!any.is_match(""));
+
+        let mut caret = Matcher::new("[a^]").unwrap();
+        assert!(caret.is_match("^"));
+        assert!(!caret.is_match("b"));
+    }
+
+    #[test]
+    fn escape_test() {
+        let mut matcher = Matcher::new(r"a\.b").unwrap();
+        assert_eq!(
+            matcher.tokens,
+            vec![MatcherToken::RawText(Cow::Borrowed("a")), MatcherToken::RawText(Cow::Borrowed(".")), MatcherToken::RawText(Cow::Borrowed("b"))]
+        );
+        assert!(matcher.is_match("a.b"));
+        assert!(!matcher.is_match("axb"));
+
+        let mut all = Matcher::new(r"\(\)\|\[\\").unwrap();
+        assert!(all.is_match(r"()|[\"));
+
+        let mut quantified = Matcher::new(r"1\+?2").unwrap();
+        assert!(quantified.is_match("12"));
+        assert!(quantified.is_match("1+2"));
+
+        assert_eq!(Matcher::new(r"abc\"), Err(MatcherParseError::TrailingBackslash { at: 3 }));
+        assert_eq!(Matcher::new(r"\q"), Err(MatcherParseError::UnknownEscape { at: 0 }));
+    }
+
+    #[test]
+    fn unicode_property_test() {
+        let mut letters = Matcher::new(r"\p{L}+").unwrap();
+        assert!(letters.is_match("Привет"));
+        assert!(letters.is_match("abcé"));
+        assert!(!letters.is_match("abc1"));
+        assert_eq!(letters.match_string("ж!")[0].1, "ж");
+
+        let mut numbers = Matcher::new(r"\p{N}\p{N}").unwrap();
+        assert!(numbers.is_match("٣٣"));
+        assert!(numbers.is_match("4²"));
+        assert!(!numbers.is_match("4a"));
+
+        let mut punctuation = Matcher::new(r"a\p{Punctuation}").unwrap();
+        assert!(punctuation.is_match("a!"));
+        assert!(punctuation.is_match("a…"));
+        assert!(punctuation.is_match("a。"));
+        assert!(!punctuation.is_match("a+"));
+
+        let mut not_letters = Matcher::new(r"\P{L}").unwrap();
+        assert!(not_letters.is_match("5"));
+        assert!(!not_letters.is_match("я"));
+
+        assert_eq!(Matcher::new(r"x\p{L}*\P{N}").unwrap().to_string(), r"x\p{L}*\P{N}");
+        assert_eq!(Matcher::new(r"\p{L}").unwrap().match_length_bounds(), (1, Some(4)));
+        assert_eq!(Matcher::new(r"a\p{Greek}"), Err(MatcherParseError::UnknownProperty { at: 1 }));
+        assert_eq!(Matcher::new(r"a\p{L"), Err(MatcherParseError::UnknownProperty { at: 1 }));
+        assert_eq!(Matcher::new(r"\pL"), Err(MatcherParseError::UnknownProperty { at: 0 }));
+    }
+
+    #[test]
+    fn shorthand_class_test() {
+        let mut digits = Matcher::new(r"\d\d\d").unwrap();
+        assert!(digits.is_match("042"));
+        assert!(!digits.is_match("04"));
+        assert!(!digits.is_match("0420"));
+        assert!(!digits.is_match("04a"));
+        assert!(!digits.is_match("٣٣٣"));
+
+        let mut word = Matcher::new(r"\w+").unwrap();
+        assert!(word.is_match("snake_Case_42"));
+        assert!(!word.is_match("kebab-case"));
+
+        let mut space = Matcher::new(r"a\sb").unwrap();
+        assert!(space.is_match("a b"));
+        assert!(space.is_match("a\tb"));
+        assert!(space.is_match("a\nb"));
+        assert!(!space.is_match("a_b"));
+    }
+
+    #[test]
+    fn negated_shorthand_class_test() {
+        let mut matcher = Matcher::new(r"\S\s\S").unwrap();
+        assert!(matcher.is_match("a b"));
+        assert!(matcher.is_match("💪\t!"));
+        assert!(!matcher.is_match("a  b"));
+        assert!(!matcher.is_match("ab"));
+
+        let mut not_digit = Matcher::new(r"\D").unwrap();
+        assert!(not_digit.is_match("x"));
+        assert!(!not_digit.is_match("7"));
+        assert!(!not_digit.is_match(""));
+
+        let mut not_word = Matcher::new(r"\W").unwrap();
+        assert!(not_word.is_match("-"));
+        assert!(!not_word.is_match("_"));
+
+        assert_eq!(Matcher::new(r"a\Q"), Err(MatcherParseError::UnknownEscape { at: 1 }));
+    }
+
+    #[test]
+    fn anchor_test() {
+        let mut matcher = Matcher::new("^abc$").unwrap();
+        assert_eq!(
+            matcher.tokens,
+            vec![MatcherToken::StartAnchor, MatcherToken::RawText(Cow::Borrowed("abc")), MatcherToken::EndAnchor]
+        );
+        assert_eq!(matcher.match_string("abc"), vec![(&MatcherToken::RawText(Cow::Borrowed("abc")), "abc")]);
+        assert!(matcher.is_match("abc"));
+        assert!(!matcher.is_match("abcd"));
+
+        let mut end = Matcher::new("a.$").unwrap();
+        assert!(Search::new(end.options).match_tokens(&end.tokens, "ab", 0, false).complete);
+        assert!(!Search::new(end.options).match_tokens(&end.tokens, "abc", 0, false).complete);
+        assert_eq!(end.match_string("abc").len(), 2);
+
+        let mut middle = Matcher::new("a^b").unwrap();
+        assert!(!middle.is_match("ab"));
+
+        let mut escaped = Matcher::new(r"\^\$").unwrap();
+        assert!(escaped.is_match("^$"));
+    }
+
+    #[test]
+    fn top_level_alternation_test() {
+        let mut animals = Matcher::new("cat|dog|fish").unwrap();
+        assert_eq!(animals.tokens, vec![one_of(&["cat", "dog", "fish"])]);
+        assert!(animals.is_match("cat"));
+        assert!(animals.is_match("dog"));
+        assert!(animals.is_match("fish"));
+        assert!(!animals.is_match("cow"));
+
+        let mut matcher = Matcher::new("ab(c|d)|ef").unwrap();
+        assert_eq!(
+            matcher.tokens,
+            vec![MatcherToken::OneOfText(vec![
+                vec![MatcherToken::RawText(Cow::Borrowed("ab")), one_of(&["c", "d"])],
+                vec![MatcherToken::RawText(Cow::Borrowed("ef"))],
+            ])]
+        );
+        assert!(matcher.is_match("abc"));
+        assert!(matcher.is_match("abd"));
+        assert!(matcher.is_match("ef"));
+        assert!(!matcher.is_match("abef"));
+        assert!(!matcher.is_match("abcef"));
+
+        assert_eq!(Matcher::new("a|b)"), Err(MatcherParseError::UnbalancedParen { at: 3 }));
+    }
+
+    #[test]
+    fn empty_alternative_test() {
+        assert_eq!(Matcher::new("(a||b)"), Err(MatcherParseError::EmptyAlternative { at: 3 }));
+        assert_eq!(Matcher::new("(a|)"), Err(MatcherParseError::EmptyAlternative { at: 3 }));
+        assert_eq!(Matcher::new("x(|a)"), Err(MatcherParseError::EmptyAlternative { at: 2 }));
+        assert_eq!(Matcher::new("a|"), Err(MatcherParseError::EmptyAlternative { at: 2 }));
+        assert_eq!(Matcher::new("|a"), Err(MatcherParseError::EmptyAlternative { at: 0 }));
+        assert_eq!(Matcher::new("(a||b)").unwrap_err().to_string(), "empty alternative at byte 3");
+
+        // An escaped `|` isn't a separator, so nothing here is empty.
+        assert!(Matcher::new(r"(\||a)").unwrap().is_match("|"));
+        assert!(Matcher::new("(a)?").unwrap().is_match(""));
+        assert!(Matcher::new("").unwrap().is_match(""));
+    }
+
+    #[test]
+    fn empty_group_test() {
+        assert_eq!(Matcher::new("()"), Err(MatcherParseError::EmptyGroup { at: 0 }));
+        assert_eq!(Matcher::new("a()b"), Err(MatcherParseError::EmptyGroup { at: 1 }));
+        assert_eq!(Matcher::new("(?<name>)"), Err(MatcherParseError::EmptyGroup { at: 0 }));
+        assert_eq!(Matcher::new("(a())*"), Err(MatcherParseError::EmptyGroup { at: 2 }));
+        assert_eq!(Matcher::new("()").unwrap_err().to_string(), "empty group at byte 0");
+
+        // A group which can match nothing still finishes when it's repeated.
+        let mut matcher = Matcher::new("(a?)*b").unwrap();
+        assert_eq!(matcher.match_string("b")[0].1, "b");
+        assert!(matcher.is_match("aab"));
+        assert!(!matcher.is_match("aac"));
+    }
+
+    #[test]
+    fn parse_allocation_test() {
+        // Only the list of tokens is allocated, not a list of options around it.
+        let (tokens, allocations) = count_allocations(|| Matcher::parse_pattern("abcdef", usize::MAX, false));
+        assert_eq!(tokens, Ok((vec![MatcherToken::RawText(Cow::Borrowed("abcdef"))], "")));
+        assert_eq!(allocations, 1);
+
+        // A group needs its list of options, and a list of tokens for each one.
+        let (_, allocations) = count_allocations(|| Matcher::parse_pattern("(ab|cd)", usize::MAX, false));
+        assert_eq!(allocations, 4);
+
+        // A lazy group of plain text needs neither, even once it's matched.
+        let (matcher, allocations) = count_allocations(|| Matcher::new_lazy("(ab|cd)"));
+        assert_eq!(allocations, 1);
+        let mut matcher = matcher.unwrap();
+        let (_, eager) = count_allocations(|| Matcher::new("(ab|cd)").unwrap().is_match("cd"));
+        let (matched, lazy) = count_allocations(|| matcher.is_match("cd"));
+        assert!(matched);
+        assert_eq!(lazy + 4, eager);
+        assert_eq!(matcher.tokens, [MatcherToken::LazyGroup("ab|cd")]);
+    }
+
+    /// This times parsing patterns with lots of groups in them. Run it with
+    /// `cargo test --release -- --ignored --nocapture parse_benchmark`.
+    #[test]
+    #[ignore]
+    fn parse_benchmark() {
+        let pattern = "(a|b)(cd|ef|gh)x(y|z)".repeat(20);
+        let string = "bghxy".repeat(20);
+        let iterations = 10_000;
+
+        let (_, allocations) = count_allocations(|| Matcher::parse_pattern(&pattern, usize::MAX, false));
+        let started = Instant::now();
+        for _ in 0..iterations {
+            assert!(Matcher::parse_pattern(&pattern, usize::MAX, false).is_ok());
+        }
+        let elapsed = started.elapsed();
+        println!("parse: {:?} per pattern, {allocations} allocations", elapsed / iterations);
+
+        // Every group here is plain text, so a lazy one is never made into tokens.
+        for (name, lazy_groups) in [("eager", false), ("lazy", true)] {
+            let builder = MatcherBuilder::new().lazy_groups(lazy_groups);
+            let (_, allocations) = count_allocations(|| builder.build(&pattern).unwrap().is_match(&string));
+            let started = Instant::now();
+            for _ in 0..iterations {
+                assert!(builder.build(&pattern).unwrap().is_match(&string));
+            }
+            let elapsed = started.elapsed();
+            println!("{name} new and is_match: {:?} per pattern, {allocations} allocations", elapsed / iterations);
+        }
+    }
+
+    /// This times making a matcher with big groups of words which are never
+    /// reached, both eagerly and lazily. Run it with
+    /// `cargo test --release -- --ignored --nocapture lazy_parse_benchmark`.
+    #[test]
+    #[ignore]
+    fn lazy_parse_benchmark() {
+        let group = format!("({})", (0..50).map(|i| format!("word{i}")).collect::<Vec<_>>().join("|"));
+        let pattern = format!("^q{}", group.repeat(20));
+        let iterations = 1_000;
+
+        let started = Instant::now();
+        for _ in 0..iterations {
+            assert!(!Matcher::new(&pattern).unwrap().is_match("nothing"));
+        }
+        println!("eager: {:?} per pattern", started.elapsed() / iterations);
+
+        let started = Instant::now();
+        for _ in 0..iterations {
+            assert!(!Matcher::new_lazy(&pattern).unwrap().is_match("nothing"));
+        }
+        println!("lazy: {:?} per pattern", started.elapsed() / iterations);
+    }
+
+    #[test]
+    fn new_lazy_test() {
+        // Only the group of plain text is kept as text.
+        let mut matcher = Matcher::new_lazy("a(b|c(d|e))[)x]f").unwrap();
+        let [MatcherToken::RawText(Cow::Borrowed("a")), MatcherToken::OneOfText(options), ..] = &matcher.tokens[..] else {
+            panic!("expected a group, not {:?}", matcher.tokens);
+        };
+        assert_eq!(options[1][1], MatcherToken::LazyGroup("d|e"));
+        assert_eq!(matcher.to_string(), "a(b|c(d|e))[)x]f");
+
+        assert!(matcher.is_match("ab)f"));
+        assert!(matcher.is_match("acexf"));
+        assert!(!matcher.is_match("acf"));
+        assert_eq!(matcher.match_string("acd)f")[1].1, "cd");
+
+        // A mistake anywhere in a group is the same error it is for `new`.
+        let broken = [
+            "a(b", "a()", "a(b{2,1})|c", "(a|(b|))", "(x(?<>y))", "(x[b-a])", "([a)", r"(a\q)", "(a\\", "(*a)",
+            "(a|+)", "(a{x})", r"(\p{Nope})", "((|))", "(a)|(|b)", "((a)(b)c|d))", "(a||b)", "(a|)", "(|a)", "(ab",
+        ];
+        for pattern in broken {
+            assert_eq!(Matcher::new_lazy(pattern).unwrap_err(), Matcher::new(pattern).unwrap_err(), "{pattern}");
+        }
+        for pattern in ["(a??b*?c{1,}?)", r"(^\b[^)]\p{L}\d\.$)", "((?i:x)(?<n>y)|z)"] {
+            assert!(Matcher::new_lazy(pattern).is_ok(), "{pattern}");
+        }
+
+        // The limit on alternatives counts the options of lazy groups too.
+        let builder = MatcherBuilder::new().lazy_groups(true).max_alternatives(2);
+        assert!(matches!(builder.build("x(a|b)").unwrap().tokens[1], MatcherToken::LazyGroup(_)));
+        assert_eq!(builder.build("x(a|(b|c|d))"), Err(MatcherParseError::TooManyAlternatives { at: 8 }));
+        assert_eq!(builder.lazy_groups(false).build("x(a|(b|c|d))"), Err(MatcherParseError::TooManyAlternatives { at: 8 }));
+
+        // A lazy group matches like the group `new` makes, whatever the options.
+        for lazy_groups in [false, true] {
+            let builder = MatcherBuilder::new().lazy_groups(lazy_groups);
+            let mut longest = builder.longest_alternative_first(true).build("(a|ab)").unwrap();
+            assert_eq!(longest.match_string("ab")[0].1, "ab", "{lazy_groups}");
+            assert_eq!(longest.to_string(), "(ab|a)");
+
+            let mut partial = builder.partial_alternatives(true).build("x(cat|car)").unwrap();
+            assert_eq!(partial.match_string("xca").len(), 1);
+            assert_eq!((partial.tokens_matched(), partial.furthest_byte()), (2, 3), "{lazy_groups}");
+        }
+
+        // Keeping the text doesn't stop a matcher living shorter than its pattern.
+        fn shorten<'s>(matcher: Matcher<'static>) -> Matcher<'s> {
+            matcher
+        }
+        assert!(shorten(Matcher::new_lazy("(a|b)").unwrap()).is_match("b"));
+    }
+
+    #[test]
+    fn word_set_test() {
+        let pattern = "(one|two|three|four|five|six|seven|eight|eighteen|tw)s";
+        let mut matcher = Matcher::new(pattern).unwrap();
+        let [MatcherToken::OneOfWords(set), MatcherToken::RawText(Cow::Borrowed("s"))] = &matcher.tokens[..] else {
+            panic!("expected a word set, not {:?}", matcher.tokens);
+        };
+        assert_eq!(set.words.len(), 10);
+        assert_eq!(matcher.to_string(), pattern);
+
+        assert!(matcher.is_match("ones"));
+        assert!(matcher.is_match("eighteens"));
+        assert!(matcher.is_match("eights"));
+        assert!(!matcher.is_match("nines"));
+        assert!(!matcher.is_match("one"));
+        assert_eq!(matcher.match_string("twos!")[0].1, "two");
+
+        // Options are still tried in order, and backtracked into.
+        let mut ordered = Matcher::new("(tw|two|a|b|c|d|e|f)o").unwrap();
+        assert!(matches!(ordered.tokens[0], MatcherToken::OneOfWords(_)));
+        assert_eq!(ordered.match_string("twoo")[0].1, "tw");
+        assert!(ordered.is_match("twoo"));
+
+        // Small groups, and groups of more than plain text, aren't word sets.
+        assert!(matches!(Matcher::new("(a|b|c)").unwrap().tokens[0], MatcherToken::OneOfText(_)));
+        assert!(matches!(Matcher::new("(a|b|c|d|e|f|g|h.)").unwrap().tokens[0], MatcherToken::OneOfText(_)));
+
+        let mut top_level = Matcher::new("a|b|c|d|e|f|g|💪").unwrap();
+        assert!(matches!(top_level.tokens[0], MatcherToken::OneOfWords(_)));
+        assert!(top_level.is_match("💪"));
+
+        let mut insensitive = Matcher::new_case_insensitive(pattern).unwrap();
+        assert!(insensitive.is_match("SEVENS"));
+    }
+
+    /// This times a group made from a long word list, as a `OneOfWords`
+    /// and as a plain `OneOfText`. Run it with
+    /// `cargo test --release -- --ignored --nocapture word_set_benchmark`.
+    #[test]
+    #[ignore]
+    fn word_set_benchmark() {
+        let words: Vec<String> = (0..500).map(|i| format!("word{i}")).collect();
+        let pattern = format!("({})", words.join("|"));
+        let mut trie = Matcher::new(&pattern).unwrap();
+        let mut linear = Matcher {
+            text: pattern.as_str().into(),
+            tokens: vec![MatcherToken::OneOfText(
+                words.iter().map(|word| vec![MatcherToken::RawText(Cow::Borrowed(word))]).collect(),
+            )],
+            options: MatchOptions::default(),
+            progress: Progress::default(),
+        };
+
+        for (name, matcher) in [("trie", &mut trie), ("linear", &mut linear)] {
+            let started = Instant::now();
+            for word in &words {
+                assert!(matcher.is_match(word));
+            }
+            println!("{name}: {:?}", started.elapsed());
+        }
+    }
+
+    /// This times finding raw text in ASCII log lines, matching case and ignoring it.
+    /// Run it with `cargo test --release -- --ignored --nocapture raw_text_benchmark`.
+    #[test]
+    #[ignore]
+    fn raw_text_benchmark() {
+        let log: String = (0..20_000).map(|i| format!("2024-01-01 12:00:{:02} INFO request {i} served\n", i % 60)).collect();
+        let mut sensitive = Matcher::new("request 1999").unwrap();
+        let mut insensitive = Matcher::new_case_insensitive("REQUEST 1999").unwrap();
+
+        for (name, matcher) in [("matching case", &mut sensitive), ("ignoring case", &mut insensitive)] {
+            let started = Instant::now();
+            assert_eq!(matcher.count_matches(&log), 11);
+            println!("{name}: {:?}", started.elapsed());
+        }
+    }
+
+    #[test]
+    fn word_boundary_test() {
+        let mut cat = Matcher::new(r"\bcat\b").unwrap();
+        assert_eq!(
+            cat.tokens,
+            vec![MatcherToken::WordBoundary, MatcherToken::RawText(Cow::Borrowed("cat")), MatcherToken::WordBoundary]
+        );
+        assert_eq!(cat.find("a cat sat"), Some((2, 5)));
+        assert_eq!(cat.find("category"), None);
+        assert_eq!(cat.find("bobcat"), None);
+        assert_eq!(cat.find("cat"), Some((0, 3)));
+        assert_eq!(cat.find("the cat"), Some((4, 7)));
+        assert_eq!(cat.find("cat, then"), Some((0, 3)));
+        assert_eq!(cat.find("_cat"), None);
+        assert_eq!(cat.match_string("cat!"), vec![(&MatcherToken::RawText(Cow::Borrowed("cat")), "cat")]);
+        assert_eq!(cat.to_string(), r"\bcat\b");
+
+        // Only ASCII counts as a word character, like `\w`.
+        assert_eq!(cat.find("écat"), Some((2, 5)));
+        assert!(!Matcher::new(r"\b").unwrap().is_match(""));
+    }
+
+    #[test]
+    fn nested_group_test() {
+        let mut matcher = Matcher::new("(foo(bar|baz))").unwrap();
+        assert_eq!(
+            matcher.tokens,
+            vec![MatcherToken::OneOfText(vec![vec![MatcherToken::RawText(Cow::Borrowed("foo")), one_of(&["bar", "baz"])]])]
+        );
+        assert!(matcher.is_match("foobar"));
+        assert!(matcher.is_match("foobaz"));
+        assert!(!matcher.is_match("foo"));
+        assert!(!matcher.is_match("foobat"));
+
+        let mut mixed = Matcher::new("((a|b)c|d.)e").unwrap();
+        assert!(mixed.is_match("ace"));
+        assert!(mixed.is_match("bce"));
+        assert!(mixed.is_match("d💪e"));
+        assert!(!mixed.is_match("abe"));
+        assert_eq!(mixed.match_string("bcex")[0].1, "bc");
+
+        // A `.` in an option is a wildcard, just like outside of a group.
+        let mut dot = Matcher::new("(a.b|c)").unwrap();
+        assert_eq!(
+            dot.tokens,
+            vec![MatcherToken::OneOfText(vec![
+                vec![MatcherToken::RawText(Cow::Borrowed("a")), MatcherToken::WildCard, MatcherToken::RawText(Cow::Borrowed("b"))],
+                vec![MatcherToken::RawText(Cow::Borrowed("c"))],
+            ])]
+        );
+        assert!(dot.is_match("axb"));
+        assert!(dot.is_match("a.b"));
+        assert!(Matcher::new(r"(a\.b|c)").unwrap().is_match("a.b"));
+        assert!(!Matcher::new(r"(a\.b|c)").unwrap().is_match("axb"));
+
+        let mut escaped = Matcher::new(r"(a\)|\|)").unwrap();
+        assert!(escaped.is_match("a)"));
+        assert!(escaped.is_match("|"));
+
+        assert_eq!(Matcher::new("((a|b)c"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+        assert_eq!(Matcher::new("(a|(b)"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+        assert_eq!(Matcher::new("(a|b))"), Err(MatcherParseError::UnbalancedParen { at: 5 }));
+        assert_eq!(Matcher::new("a)"), Err(MatcherParseError::UnbalancedParen { at: 1 }));
+    }
+
+    #[test]
+    fn longest_option_test() {
+        let mut matcher = Matcher::new("(a|ab)c").unwrap();
+        assert!(matcher.is_match("abc"));
+        assert_eq!(
+            matcher.match_string("abc"),
+            vec![(&one_of(&["a", "ab"]), "ab"), (&MatcherToken::RawText(Cow::Borrowed("c")), "c")]
+        );
+
+        let mut reversed = Matcher::new("(ab|a)c").unwrap();
+        assert!(reversed.is_match("abc"));
+        assert!(reversed.is_match("ac"));
+    }
+
+    #[test]
+    fn backtracking_test() {
+        let mut options = Matcher::new("(a|ab)b").unwrap();
+        assert!(options.is_match("ab"));
+        assert!(options.is_match("abb"));
+
+        let mut star = Matcher::new(".*abc").unwrap();
+        assert!(star.is_match("abc"));
+        assert!(star.is_match("xyzabc"));
+        assert!(star.is_match("abcabc"));
+        assert!(!star.is_match("abcab"));
+
+        let mut bounded = Matcher::new("a{2,4}ab").unwrap();
+        assert!(bounded.is_match("aaab"));
+        assert!(bounded.is_match("aaaaab"));
+        assert!(!bounded.is_match("aab"));
+
+        let mut optional = Matcher::new("ab?bc").unwrap();
+        assert!(optional.is_match("abc"));
+        assert!(optional.is_match("abbc"));
+
+        // The best attempt at `x.*yz` on `xabyq` gets through two tokens.
+        let mut partial = Matcher::new("x.*yz").unwrap();
+        let star_token = MatcherToken::Repeat { inner: Box::new(MatcherToken::WildCard), min: 0, max: None, greedy: true };
+        assert_eq!(
+            partial.match_string("xabyq"),
+            vec![(&MatcherToken::RawText(Cow::Borrowed("x")), "x"), (&star_token, "abyq")]
+        );
+        assert_eq!(partial.progress.most_tokens_matched, 2);
+        assert!(!partial.is_match("xabyq"));
+        assert_eq!(partial.progress.most_tokens_matched, 2);
+
+        // A group which can match nothing still gets repeated enough times.
+        let mut empty_repeats = Matcher::new("(a?){3}b").unwrap();
+        assert!(empty_repeats.is_match("b"));
+        assert!(empty_repeats.is_match("aab"));
+        assert!(!empty_repeats.is_match("aaaab"));
+    }
+
+    #[test]
+    fn unicode_prefix_test() {
+        // `💪` and `💩` only differ in their last byte, and `é` and `è` only differ
+        // in their second, so none of these options match part of a character.
+        let mut matcher = Matcher::new("(💪|é)x").unwrap();
+        assert_eq!(matcher.match_string("💩x"), vec![]);
+        assert_eq!(matcher.match_string("èx"), vec![]);
+        assert!(matcher.is_match("💪x"));
+        assert!(matcher.is_match("éx"));
+
+        let mut raw = Matcher::new("a💪").unwrap();
+        assert_eq!(raw.match_string("a💩"), vec![]);
+        assert_eq!(raw.match_string("a💪💩"), vec![(&MatcherToken::RawText(Cow::Borrowed("a💪")), "a💪")]);
+    }
+
+    #[test]
+    fn reset_progress_test() {
+        let mut matcher = Matcher::new("abc(d|e|f).").unwrap();
+        matcher.match_string("abcdx");
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        matcher.match_string("abc");
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        matcher.reset_progress();
+        assert_eq!(matcher.tokens_matched(), 0);
+        matcher.match_string("abc");
+        assert_eq!(matcher.tokens_matched(), 1);
+    }
+
+    #[test]
+    fn furthest_byte_test() {
+        let mut matcher = Matcher::new("[a-z]+@[a-z]+").unwrap();
+        assert_eq!(matcher.furthest_byte(), 0);
+        matcher.match_string("hello!");
+        assert_eq!(matcher.tokens_matched(), 1);
+        assert_eq!(matcher.furthest_byte(), 5);
+
+        // It keeps the furthest offset from any call.
+        matcher.match_string("hi@");
+        assert_eq!(matcher.furthest_byte(), 5);
+        assert!(!matcher.is_match("longer@x!"));
+        assert_eq!(matcher.tokens_matched(), 3);
+        assert_eq!(matcher.furthest_byte(), 8);
+
+        matcher.reset_progress();
+        assert_eq!(matcher.furthest_byte(), 0);
+        assert_eq!(matcher.tokens_matched(), 0);
+    }
+
+    #[test]
+    fn partial_alternatives_test() {
+        let mut typing = MatcherBuilder::new().partial_alternatives(true).build("I have a (cat|car)").unwrap();
+        assert_eq!(typing.match_string("I have a ca").len(), 1);
+        assert_eq!(typing.tokens_matched(), 2);
+        assert_eq!(typing.furthest_byte(), 11);
+
+        // Without the flag, the group doesn't count until it matches.
+        let mut exact = Matcher::new("I have a (cat|car)").unwrap();
+        exact.match_string("I have a ca");
+        assert_eq!(exact.tokens_matched(), 1);
+        assert_eq!(exact.furthest_byte(), 9);
+
+        let partial = MatcherBuilder::new().partial_alternatives(true);
+        let mut matcher = partial.build("(cat|car)").unwrap();
+        assert!(matcher.match_string("ca").is_empty());
+        assert_eq!((matcher.tokens_matched(), matcher.furthest_byte()), (1, 2));
+        matcher.reset_progress();
+        matcher.match_string("dog");
+        assert_eq!((matcher.tokens_matched(), matcher.furthest_byte()), (0, 0));
+
+        let mut words = partial.build("(zero|one|two|three|four|five|six|seven)").unwrap();
+        words.match_string("thr");
+        assert_eq!((words.tokens_matched(), words.furthest_byte()), (1, 3));
+        let mut ignoring = partial.build("(?i:cat|car)").unwrap();
+        ignoring.match_string("CA");
+        assert_eq!((ignoring.tokens_matched(), ignoring.furthest_byte()), (1, 2));
+    }
+
+    #[test]
+    fn find_test() {
+        let mut matcher = Matcher::new("b+c").unwrap();
+        assert_eq!(matcher.find("abbcd"), Some((1, 4)));
+        assert_eq!(matcher.find("bc"), Some((0, 2)));
+        assert_eq!(matcher.find("abd"), None);
+        assert_eq!(matcher.find(""), None);
+
+        let haystack = "💪💪bc";
+        let (start, end) = matcher.find(haystack).unwrap();
+        assert_eq!((start, end), (8, 10));
+        assert_eq!(&haystack[start..end], "bc");
+
+        let mut anchored = Matcher::new("^b").unwrap();
+        assert_eq!(anchored.find("ab"), None);
+        assert_eq!(anchored.find("ba"), Some((0, 1)));
+
+        let mut end = Matcher::new("$").unwrap();
+        assert_eq!(end.find("💪"), Some((4, 4)));
+    }
+
+    #[test]
+    fn find_all_test() {
+        let mut matcher = Matcher::new("(a|b)").unwrap();
+        assert_eq!(matcher.find_all("abcab"), vec![(0, 1), (1, 2), (3, 4), (4, 5)]);
+        assert_eq!(matcher.find_all("xyz"), vec![]);
+
+        let mut runs = Matcher::new("a+").unwrap();
+        assert_eq!(runs.find_all("aaxaaa"), vec![(0, 2), (3, 6)]);
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.find_all("a💪"), vec![(0, 0), (1, 1), (5, 5)]);
+        assert_eq!(empty.find_all("xa"), vec![(0, 1), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn count_matches_test() {
+        let mut matcher = Matcher::new("(a|b)").unwrap();
+        assert_eq!(matcher.count_matches("abcab, xyz; ba!"), 6);
+        assert_eq!(matcher.count_matches("xyz"), 0);
+        assert_eq!(matcher.count_matches(""), 0);
+
+        // A match of nothing still moves the search on past it.
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.count_matches("a💪"), 3);
+        assert_eq!(empty.count_matches(""), 1);
+    }
+
+    #[test]
+    fn find_all_overlapping_test() {
+        let mut pair = Matcher::new("aa").unwrap();
+        assert_eq!(pair.find_all_overlapping("aaaa"), vec![(0, 2), (1, 3), (2, 4)]);
+        assert_eq!(pair.find_all("aaaa"), vec![(0, 2), (2, 4)]);
+        assert_eq!(pair.find_all_overlapping("a💪aa"), vec![(5, 7)]);
+        assert_eq!(pair.find_all_overlapping(""), vec![]);
+
+        // Each match is as long as it would be on its own.
+        let mut runs = Matcher::new("a+").unwrap();
+        assert_eq!(runs.find_all_overlapping("aab"), vec![(0, 2), (1, 2)]);
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.find_all_overlapping("xa"), vec![(0, 1), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn match_mask_test() {
+        let mut matcher = Matcher::new("ab").unwrap();
+        assert_eq!(matcher.match_mask("abéab"), vec![(0, true), (1, false), (2, false), (4, true), (5, false), (6, false)]);
+        assert_eq!(matcher.match_mask(""), vec![(0, false)]);
+        assert_eq!(matcher.furthest_byte(), 6);
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.match_mask("ax"), vec![(0, true), (1, true), (2, true)]);
+
+        let mut word = MatcherBuilder::new().whole_words(true).build("cat").unwrap();
+        assert_eq!(word.match_mask("cats cat").into_iter().filter(|&(_, matched)| matched).collect::<Vec<_>>(), vec![(5, true)]);
+    }
+
+    #[test]
+    fn matches_iter_test() {
+        let mut matcher = Matcher::new("a+").unwrap();
+        let mut matches = matcher.matches_iter("aabaaab");
+        assert_eq!(matches.next(), Some((0, 2)));
+        assert_eq!(matches.next(), Some((3, 6)));
+        assert_eq!(matches.next(), None);
+        assert_eq!(matches.next(), None);
+
+        // Only the matches which are asked for get searched for.
+        assert_eq!(matcher.matches_iter("a a a").take(2).collect::<Vec<_>>(), vec![(0, 1), (2, 3)]);
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.matches_iter("💪").collect::<Vec<_>>(), vec![(0, 0), (4, 4)]);
+        assert_eq!(empty.matches_iter("").collect::<Vec<_>>(), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn replace_test() {
+        let mut matcher = Matcher::new("(cat|dog)").unwrap();
+        assert_eq!(matcher.replace("no pets", "pet"), "no pets");
+        assert_eq!(matcher.replace("cat and dog", "pet"), "pet and dog");
+        assert_eq!(matcher.replace("a dog, a cat", "💪"), "a 💪, a cat");
+        assert_eq!(matcher.replace("", "pet"), "");
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.replace("ab", "-"), "-ab");
+    }
+
+    #[test]
+    fn replace_all_test() {
+        let mut matcher = Matcher::new("(cat|dog)").unwrap();
+        assert_eq!(matcher.replace_all("cat and dog", "pet"), "pet and pet");
+        assert_eq!(matcher.replace_all("a dog, a cat", "💪"), "a 💪, a 💪");
+        assert_eq!(matcher.replace_all("no pets", "pet"), "no pets");
+        assert_eq!(matcher.replace_all("", "pet"), "");
+
+        let mut digits = Matcher::new(r"\d+").unwrap();
+        assert_eq!(digits.replace_all("call 555 1234", "#"), "call # #");
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.replace_all("ab", "-"), "-a-b-");
+    }
+
+    #[test]
+    fn replacement_template_test() {
+        let mut swap = Matcher::new(r"(?<first>\w+) (?<last>\w+)").unwrap();
+        assert_eq!(swap.try_replace_all("Ada Lovelace, Alan Turing", "$last $first").unwrap(), "Lovelace Ada, Turing Alan");
+        assert_eq!(swap.try_replace("Ada Lovelace", "<$0>").unwrap(), "<Ada Lovelace>");
+        assert_eq!(swap.try_replace_all("Ada Lovelace", "$$first costs $$$last").unwrap(), "$first costs $Lovelace");
+
+        // A group in an option which wasn't taken gives nothing.
+        let mut price = Matcher::new(r"(?<pounds>\d+)(p|\.(?<pence>\d\d))").unwrap();
+        assert_eq!(price.try_replace_all("3p and 4.50", "[$pounds|$pence]").unwrap(), "[3|] and [4|50]");
+
+        assert_eq!(swap.try_replace_all("Ada Lovelace", "$middle"), Err(ReplacementError::UnknownGroup { at: 0 }));
+        assert_eq!(swap.try_replace("no match", "$first $1"), Err(ReplacementError::UnknownGroup { at: 7 }));
+        assert_eq!(swap.try_replace_all("Ada Lovelace", "costs $"), Err(ReplacementError::MissingGroupName { at: 6 }));
+        assert_eq!(swap.try_replace_all("Ada Lovelace", "$ each"), Err(ReplacementError::MissingGroupName { at: 0 }));
+        // The plain versions don't look for `$` at all.
+        assert_eq!(swap.replace_all("Ada Lovelace", "$last $"), "$last $");
+        assert_eq!(swap.replace("Ada Lovelace, Alan Turing", "$$0"), "$$0, Alan Turing");
+        assert_eq!(ReplacementError::UnknownGroup { at: 3 }.to_string(), "no group in the pattern has the name at byte 3");
+    }
+
+    #[test]
+    fn find_all_in_reader_test() {
+        let log = "ok 12\nerror 404\r\nok\nerror 500";
+        let mut errors = Matcher::new(r"error \d+").unwrap();
+        let found = errors.find_all_in_reader(log.as_bytes()).unwrap();
+        assert_eq!(found, vec![(6, 15), (20, 29)]);
+        assert_eq!(&log[found[0].0..found[0].1], "error 404");
+        assert_eq!(&log[found[1].0..found[1].1], "error 500");
+
+        // A match can go over a line break, and `^` and `$` are the start and end of it all.
+        let mut across = Matcher::new(r"12\serror").unwrap();
+        assert_eq!(across.find_all_in_reader(log.as_bytes()).unwrap(), vec![(3, 11)]);
+        let mut whole = Matcher::new(r"^ok|\d+$").unwrap();
+        assert_eq!(whole.find_all_in_reader(log.as_bytes()).unwrap(), vec![(0, 2), (26, 29)]);
+
+        let reader = io::BufReader::with_capacity(2, "b💪b\nbb".as_bytes());
+        assert_eq!(Matcher::new("b").unwrap().find_all_in_reader(reader).unwrap(), vec![(0, 1), (5, 6), (7, 8), (8, 9)]);
+
+        // Read in tiny pieces, matches which go over where the reads were cut still
+        // come out like they do for `find_all`.
+        let text = "one two  three\nfour 💪 cat\r\ncats catalog x";
+        let patterns = [r"\w+", r"t\w*e", r"(cat|cats)\b", "c.*?g", r"\s+", "x$|^o", "", r"\b", "o?", "(?i:T)w", "💪 c"];
+        for pattern in patterns {
+            let expected = Matcher::new(pattern).unwrap().find_all(text);
+            for capacity in 1..6 {
+                let reader = io::BufReader::with_capacity(capacity, text.as_bytes());
+                let found = Matcher::new(pattern).unwrap().find_all_in_reader(reader).unwrap();
+                assert_eq!(found, expected, "{pattern} read {capacity} bytes at a time");
+            }
+        }
+        let mut words = MatcherBuilder::new().whole_words(true).build("cat").unwrap();
+        let reader = io::BufReader::with_capacity(3, "cat cats cat".as_bytes());
+        assert_eq!(words.find_all_in_reader(reader).unwrap(), vec![(0, 3), (9, 12)]);
+
+        // One line much bigger than a read is fine, since most of it can't be part of a match.
+        let big = io::Read::chain(io::Read::take(io::repeat(b'x'), 1 << 20), "needle\n".as_bytes());
+        let mut needle = Matcher::new("needle$").unwrap();
+        assert_eq!(needle.find_all_in_reader(io::BufReader::new(big)).unwrap(), vec![]);
+        let big = io::Read::chain(io::Read::take(io::repeat(b'x'), 1 << 20), "needle".as_bytes());
+        assert_eq!(needle.find_all_in_reader(io::BufReader::new(big)).unwrap(), vec![(1 << 20, (1 << 20) + 6)]);
+
+        let invalid: &[u8] = &[b'a', 0xFF, b'\n'];
+        let error = Matcher::new("a").unwrap().find_all_in_reader(invalid).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let cut_off: &[u8] = &[b'a', 0xF0, 0x9F];
+        let error = Matcher::new("a").unwrap().find_all_in_reader(cut_off).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn split_test() {
+        let mut matcher = Matcher::new("(,|;)").unwrap();
+        assert_eq!(matcher.split("a,b;c"), vec!["a", "b", "c"]);
+        assert_eq!(matcher.split(",a,,b;"), vec!["", "a", "", "b", ""]);
+        assert_eq!(matcher.split("abc"), vec!["abc"]);
+        assert_eq!(matcher.split(""), vec![""]);
+
+        let mut spaces = Matcher::new(r"\s+").unwrap();
+        assert_eq!(spaces.split("hello   big\tworld"), vec!["hello", "big", "world"]);
+
+        let mut empty = Matcher::new("x*").unwrap();
+        assert_eq!(empty.split("ab"), vec!["", "a", "b", ""]);
+        assert_eq!(empty.split("axb"), vec!["", "a", "", "b", ""]);
+    }
+
+    #[test]
+    fn from_str_test() {
+        let owned: MatcherOwned = {
+            let from_config = "abc(d|e|f).".to_string();
+            from_config.parse().unwrap()
+        };
+        assert_eq!(owned.matcher(), Matcher::new("abc(d|e|f).").unwrap());
+
+        let mut matcher = owned.matcher();
+        assert!(matcher.is_match("abcdx"));
+        assert_eq!(matcher.progress.most_tokens_matched, 3);
+        assert_eq!(owned.matcher().progress.most_tokens_matched, 0);
+
+        let broken: Result<MatcherOwned, _> = "abc(d|e|f.".parse();
+        assert_eq!(broken, Err(MatcherParseError::UnbalancedParen { at: 3 }));
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let error = Matcher::new("ab(c|d").unwrap_err();
+        assert_eq!(error, MatcherParseError::UnbalancedParen { at: 2 });
+        assert_eq!(error.to_string(), "unbalanced parenthesis at byte 2");
+
+        // Offsets are in bytes, so they can be used to slice the pattern.
+        let pattern = "💪[a-z";
+        let Err(MatcherParseError::UnclosedClass { at }) = Matcher::new(pattern) else {
+            panic!("expected an unclosed class");
+        };
+        assert_eq!(&pattern[at..], "[a-z");
+
+        assert_eq!(
+            Matcher::new(r"a\").unwrap_err().to_string(),
+            r"nothing to escape after `\` at byte 1"
+        );
+
+        let boxed: Box<dyn std::error::Error> = Box::new(MatcherParseError::NothingToRepeat { at: 0 });
+        assert_eq!(boxed.to_string(), "nothing to repeat before byte 0");
+    }
+
+    #[test]
+    fn token_display_test() {
+        assert_eq!(MatcherToken::RawText(Cow::Borrowed("abc")).to_string(), "abc");
+        assert_eq!(MatcherToken::WildCard.to_string(), ".");
+        assert_eq!(one_of(&["d", "e", "f"]).to_string(), "(d|e|f)");
+        assert_eq!(MatcherToken::RawText(Cow::Borrowed("a.b|c")).to_string(), r"a\.b\|c");
+        assert_eq!(MatcherToken::CharClass(CharSet::shorthand('D').unwrap()).to_string(), r"\D");
+
+        let optional = MatcherToken::Optional(Box::new(MatcherToken::RawText(Cow::Borrowed("ab"))));
+        assert_eq!(optional.to_string(), "(ab)?");
+
+        let patterns = [
+            "abc(d|e|f).",
+            "(foo(bar|baz))x",
+            r"a\.b\(c\)\\",
+            "ab?c*d+e{2}f{2,}g{2,3}",
+            "(cat|dog)+.{0,4}",
+            "[a-z0-9_][^abc][^][-a][a^][ -z]",
+            r"\d\w\s\D\W\S",
+            "^a$",
+        ];
+        for pattern in patterns {
+            let matcher = Matcher::new(pattern).unwrap();
+            let displayed: String = matcher.tokens.iter().map(ToString::to_string).collect();
+            assert_eq!(Matcher::new(&displayed).unwrap().tokens, matcher.tokens, "{pattern} became {displayed}");
+        }
+    }
+
+    #[test]
+    fn matcher_display_test() {
+        let matcher = Matcher::new("abc(d|e|f).").unwrap();
+        assert_eq!(matcher.to_string(), "abc(d|e|f).");
+
+        let mut changed = Matcher::new("abc").unwrap();
+        changed.tokens.push(MatcherToken::WildCard);
+        assert_eq!(changed.text, "abc");
+        assert_eq!(changed.to_string(), "abc.");
+
+        assert_eq!(Matcher::new("").unwrap().to_string(), "");
+    }
+
+    #[test]
+    fn remainder_test() {
+        let mut matcher = Matcher::new("abc(d|e|f).").unwrap();
+
+        let input = "abcd💪 and more".to_string();
+        let (answer, rest) = matcher.match_with_remainder(&input);
+        assert_eq!(answer.len(), 3);
+        assert_eq!(rest, " and more");
+
+        let (answer, rest) = matcher.match_with_remainder("abcgh");
+        assert_eq!(answer, vec![(&MatcherToken::RawText(Cow::Borrowed("abc")), "abc")]);
+        assert_eq!(rest, "gh");
+
+        let (answer, rest) = matcher.match_with_remainder("xyz");
+        assert_eq!(answer, vec![]);
+        assert_eq!(rest, "xyz");
+
+        // The rest of one match can be given straight to the next matcher.
+        let mut word = Matcher::new(r"\w+").unwrap();
+        let mut space = Matcher::new(r"\s+").unwrap();
+        let (_, rest) = word.match_with_remainder("hello   world");
+        let (_, rest) = space.match_with_remainder(rest);
+        assert_eq!(rest, "world");
+    }
+
+    #[test]
+    fn clone_test() {
+        let template = Matcher::new("abc(d|e|f).[a-z]+").unwrap();
+        let mut first = template.clone();
+        let mut second = template.clone();
+
+        assert!(first.is_match("abcdxyz"));
+        assert_eq!(first.tokens_matched(), 4);
+        assert_eq!(second.tokens_matched(), 0);
+        assert_eq!(template.tokens_matched(), 0);
+
+        assert!(!second.is_match("ab"));
+        assert_eq!(second.tokens_matched(), 0);
+        assert_eq!(second.tokens, first.tokens);
+
+        // The clone still borrows the same text as the original.
+        assert!(std::ptr::eq(first.text.as_ref(), template.text.as_ref()));
+    }
+
+    #[test]
+    fn hash_test() {
+        let mut cache = HashSet::new();
+        let mut matcher = Matcher::new("(cat|dog)s?").unwrap();
+        cache.insert(matcher.clone());
+
+        assert!(!matcher.is_match("ca"));
+        assert_eq!(matcher.tokens_matched(), 0);
+        assert!(matcher.is_match("dogs"));
+        assert_eq!(matcher.tokens_matched(), 2);
+        assert!(cache.contains(&matcher));
+        assert!(cache.contains(&Matcher::new("(cat|dog)s?").unwrap()));
+
+        assert!(!cache.contains(&Matcher::new("(dog|cat)s?").unwrap()));
+        assert!(!cache.contains(&Matcher::new_case_insensitive("(cat|dog)s?").unwrap()));
+        assert!(cache.insert(Matcher::new(r"[a-z]+\b(x|y|z|w|v|u|t|s)").unwrap()));
+        assert!(!cache.insert(Matcher::new(r"[a-z]+\b(x|y|z|w|v|u|t|s)").unwrap()));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn new_glob_test() {
+        let mut text_files = Matcher::new_glob("*.txt").unwrap();
+        assert!(text_files.is_match("notes.txt"));
+        assert!(text_files.is_match(".txt"));
+        assert!(!text_files.is_match("notes.txt.bak"));
+        assert!(!text_files.is_match("notesXtxt"));
+        assert_eq!(text_files.to_string(), r".*\.txt");
+
+        let mut logs = Matcher::new_glob("file?.log").unwrap();
+        assert!(logs.is_match("file1.log"));
+        assert!(logs.is_match("fileé.log"));
+        assert!(!logs.is_match("file.log"));
+        assert!(!logs.is_match("file12.log"));
+        assert_eq!(logs.tokens(), &[MatcherToken::RawText(Cow::Borrowed("file")), MatcherToken::WildCard, MatcherToken::RawText(Cow::Borrowed(".log"))]);
+
+        // Anything which isn't `*` or `?` is literal, even if it's special in a pattern.
+        let mut brackets = Matcher::new_glob("[a](b|c)*").unwrap();
+        assert!(brackets.is_match("[a](b|c) copy"));
+        assert!(!brackets.is_match("ab"));
+        assert!(Matcher::new_glob("**").unwrap().is_match("anything"));
+        assert_eq!(Matcher::new_glob(""), Matcher::new(""));
+    }
+
+    #[test]
+    fn new_literal_test() {
+        let mut literal = Matcher::new_literal("a(b).c");
+        assert_eq!(literal.tokens, vec![MatcherToken::RawText(Cow::Borrowed("a(b).c"))]);
+        assert_eq!(literal.text, r"a\(b\)\.c");
+        assert!(literal.is_match("a(b).c"));
+        assert!(!literal.is_match("abxc"));
+        assert_eq!(literal.find("see a(b).c!"), Some((4, 10)));
+        assert!(Matcher::new(&literal.text).unwrap().is_match("a(b).c"));
+
+        let mut everything = Matcher::new_literal(r"^.()|[]?*+{}$\");
+        assert!(everything.is_match(r"^.()|[]?*+{}$\"));
+        assert!(Matcher::new_literal("").is_empty());
+    }
+
+    #[test]
+    fn from_tokens_test() {
+        let mut built = Matcher::from_tokens(vec![
+            MatcherToken::RawText(Cow::Borrowed("a.b")),
+            one_of(&["c", "d"]),
+            MatcherToken::Repeat { inner: Box::new(MatcherToken::WildCard), min: 1, max: Some(2), greedy: true },
+        ]);
+        assert_eq!(built.text, r"a\.b(c|d).{1,2}");
+        assert_eq!(built.tokens_matched(), 0);
+
+        let mut parsed = Matcher::new(r"a\.b(c|d).{1,2}").unwrap();
+        for string in ["a.bcx", "a.bdxy", "a.bc", "a.bcxyz", "axbcx", ""] {
+            assert_eq!(built.is_match(string), parsed.is_match(string), "{string:?}");
+            assert_eq!(built.match_len(string), parsed.match_len(string), "{string:?}");
+        }
+    }
+
+    #[test]
+    fn normalize_test() {
+        let mut long = Matcher::new("^^x{0,1}(y)(z|w){1}(ab)+q{0}$").unwrap();
+        let mut short = Matcher::new("^x?y(z|w)(ab)+$").unwrap();
+        assert_ne!(long, short);
+
+        long.normalize();
+        short.normalize();
+        assert_eq!(long, short);
+        assert_eq!(long.text, "^x?y(z|w)(ab)+$");
+        assert_eq!(
+            long.tokens,
+            vec![
+                MatcherToken::StartAnchor,
+                MatcherToken::Optional(Box::new(MatcherToken::RawText(Cow::Borrowed("x")))),
+                MatcherToken::RawText(Cow::Borrowed("y")),
+                one_of(&["z", "w"]),
+                MatcherToken::Repeat { inner: Box::new(MatcherToken::RawText(Cow::Borrowed("ab"))), min: 1, max: None, greedy: true },
+                MatcherToken::EndAnchor,
+            ]
+        );
+        assert!(long.is_match("xyzabab"));
+        assert!(!long.is_match("xyz"));
+
+        // A group with several tokens is spliced into the tokens around it, and
+        // text next to text is joined.
+        let mut spliced = Matcher::new("a(b.)c").unwrap();
+        spliced.normalize();
+        assert_eq!(
+            spliced.tokens,
+            vec![MatcherToken::RawText(Cow::Borrowed("ab")), MatcherToken::WildCard, MatcherToken::RawText(Cow::Borrowed("c"))]
+        );
+        let mut joined = Matcher::new("(a)b(c)").unwrap();
+        joined.normalize();
+        assert_eq!(joined, Matcher::new("abc").unwrap());
+
+        // However the text was split up, it's one token afterwards.
+        let mut whole = Matcher::new("abc").unwrap();
+        let mut split = Matcher::from_tokens(vec![MatcherToken::RawText(Cow::Borrowed("ab")), MatcherToken::RawText(Cow::Borrowed("c"))]);
+        assert_ne!(whole, split);
+        whole.normalize();
+        split.normalize();
+        assert_eq!(whole, split);
+        assert_eq!(split.tokens, vec![MatcherToken::RawText(Cow::Borrowed("abc"))]);
+        assert!(split.is_match("abc"));
+
+        // Named groups are kept, so they can still be captured.
+        let mut named = Matcher::new("(?<x>(a))").unwrap();
+        named.normalize();
+        assert_eq!(named.to_string(), "(?<x>a)");
+    }
+
+    #[test]
+    fn tokens_test() {
+        let matcher = Matcher::new("a.b(c|.)..").unwrap();
+        let wildcards = matcher.tokens().iter().filter(|token| **token == MatcherToken::WildCard).count();
+        assert_eq!(wildcards, 3);
+        assert_eq!(matcher.len(), 6);
+        assert!(!matcher.is_empty());
+
+        let empty = Matcher::new("").unwrap();
+        assert_eq!(empty.tokens(), &[]);
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn matches_empty_test() {
+        assert!(Matcher::new("").unwrap().matches_empty());
+        assert!(Matcher::new("a*").unwrap().matches_empty());
+        assert!(!Matcher::new("abc").unwrap().matches_empty());
+
+        assert!(Matcher::new("^$").unwrap().matches_empty());
+        assert!(Matcher::new(r"\bx?(y|z*){2,}").unwrap().matches_empty());
+        assert!(Matcher::new("(ab|c?)").unwrap().matches_empty());
+        assert!(!Matcher::new("(ab|c)+").unwrap().matches_empty());
+        assert!(!Matcher::new("a*.").unwrap().matches_empty());
+        assert!(!Matcher::new("[^a]*[b]").unwrap().matches_empty());
+
+        // It agrees with whether `find_all` can find a match of nothing.
+        for pattern in ["x*", "x?y*", "x+", "(a|x*)"] {
+            let mut matcher = Matcher::new(pattern).unwrap();
+            assert_eq!(matcher.matches_empty(), matcher.find_all("").contains(&(0, 0)), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn analyze_test() {
+        let (matcher, advice) = Matcher::analyze(r"[a-z]+@(com|org)\b");
+        assert_eq!(matcher, Some(Matcher::new(r"[a-z]+@(com|org)\b").unwrap()));
+        assert_eq!(advice, Vec::<String>::new());
+
+        let (matcher, advice) = Matcher::analyze("x.*.*(a|b|a)c{1}");
+        assert!(matcher.unwrap().is_match("xyzbc"));
+        assert_eq!(
+            advice,
+            vec![
+                "`.*.*` matches the same as `.*`",
+                "the option `a` is in `(a|b|a)` more than once",
+                "`c{1}` matches the same as `c`",
+            ]
+        );
+
+        let (_, advice) = Matcher::analyze("^^(x?)*");
+        assert_eq!(
+            advice,
+            vec![
+                "`^^` matches the same as `^`",
+                "`(x?)*` repeats `(x?)`, which can already match nothing",
+                "the pattern can match nothing, so `find_all` and `split` can find empty matches",
+            ]
+        );
+
+        // Advice is found inside groups, too.
+        let (_, advice) = Matcher::analyze("((?<n>a|a)+)");
+        assert_eq!(advice, vec!["the option `a` is in `(?<n>a|a)` more than once"]);
+
+        assert_eq!(Matcher::analyze("(a||b)"), (None, vec!["empty alternative at byte 3".to_string()]));
+        assert_eq!(Matcher::analyze("a)").1, vec!["unbalanced parenthesis at byte 1"]);
+    }
+
+    #[test]
+    fn try_from_test() {
+        let mut matcher = Matcher::try_from("(cat|dog)s").unwrap();
+        assert_eq!(matcher, Matcher::new("(cat|dog)s").unwrap());
+        assert!(matcher.is_match("dogs"));
+        assert_eq!(Matcher::try_from("(cat"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+
+        let converted: Result<Matcher, _> = "a{2,1}".try_into();
+        assert_eq!(converted, Err(MatcherParseError::InvalidRepetition { at: 1 }));
+    }
+
+    #[test]
+    fn into_iter_test() {
+        let matcher = Matcher::new("abc(d|e|f).").unwrap();
+        let mut tokens = Vec::new();
+        for token in &matcher {
+            tokens.push(token);
+        }
+        assert_eq!(tokens, matcher.tokens.iter().collect::<Vec<_>>());
+        assert!(matches!(tokens[..], [MatcherToken::RawText(Cow::Borrowed("abc")), MatcherToken::OneOfText(_), MatcherToken::WildCard]));
+
+        let groups = (&matcher).into_iter().filter(|token| matches!(token, MatcherToken::OneOfText(_))).count();
+        assert_eq!(groups, 1);
+        assert_eq!((&Matcher::new("").unwrap()).into_iter().next(), None);
+    }
+
+    #[test]
+    fn compile_test() {
+        let program = {
+            let pattern = String::from(r"(?<key>[a-z]+)=(on|off|yes|no|true|false|none|null|0|1)\b;?");
+            let matcher = Matcher::new(&pattern).unwrap();
+            matcher.compile()
+        };
+
+        assert!(matches!(program.tokens()[2], MatcherToken::OneOfWords(_)));
+        assert_eq!(program.match_string("size=1; rest"), vec!["size", "=", "1", ";"]);
+        assert_eq!(program.match_string("flag=null"), vec!["flag", "=", "null"]);
+        assert_eq!(program.match_string("Size=1"), Vec::<&str>::new());
+        assert!(program.is_match("debug=on"));
+        assert!(!program.is_match("debug=online"));
+        assert_eq!(program.find("set debug=yes now"), Some((4, 13)));
+        assert_eq!(program.find("nothing here"), None);
+
+        // It keeps the options, and compiles back to the same tokens.
+        let pattern = "(A|b).";
+        let matcher = Matcher::new_case_insensitive(pattern).unwrap();
+        let program = matcher.compile();
+        assert!(program.is_match("a\u{1F4AA}"));
+        assert!(!program.is_match("a\n"));
+        assert_eq!(program.tokens(), matcher.tokens);
+
+        // The tokens are lent out again for each match, but the trie isn't made again,
+        // so only the list of tokens and the list of words are allocated on top of what
+        // a matcher allocates. A copy still works once the original is gone.
+        let pattern = format!("({})", (0..20).map(|i| format!("w{i}")).collect::<Vec<_>>().join("|"));
+        let mut words = Matcher::new(&pattern).unwrap();
+        let program = words.compile();
+        let (_, program_allocations) = count_allocations(|| program.is_match("w17"));
+        let (_, matcher_allocations) = count_allocations(|| words.is_match("w17"));
+        assert_eq!(program_allocations, matcher_allocations + 2);
+        let copy = program.clone();
+        drop(program);
+        assert!(copy.is_match("w19"));
+        assert_eq!(copy, words.compile());
+
+        // Like `Matcher::find`, it only tries the start if the pattern starts with `^`.
+        let anchored = Matcher::new("^ab").unwrap().compile();
+        assert_eq!(anchored.find("abab"), Some((0, 2)));
+        assert_eq!(anchored.find("xab"), None);
+        let (_, allocations) = count_allocations(|| anchored.find(&"x".repeat(1000)));
+        assert!(allocations < 10, "{allocations}");
+    }
+
+    #[test]
+    fn match_length_bounds_test() {
+        let bounds = |pattern: &str| Matcher::new(pattern).unwrap().match_length_bounds();
+        assert_eq!(bounds("abc"), (3, Some(3)));
+        assert_eq!(bounds("a*"), (0, None));
+        assert_eq!(bounds("(x|yy)"), (1, Some(2)));
+        assert_eq!(bounds("a+b?"), (1, None));
+        assert_eq!(bounds("^(ab){2,3}.$"), (5, Some(10)));
+        assert_eq!(bounds("[a-z]\\d[^x]"), (3, Some(6)));
+        assert_eq!(bounds("[aé]"), (1, Some(2)));
+        assert_eq!(bounds("[é-ü]"), (2, Some(2)));
+        assert_eq!(bounds("[💪é]+"), (2, None));
+        assert_eq!(bounds("[^é-ü]"), (1, Some(4)));
+        assert_eq!(bounds("(\\b)*x{0}"), (0, Some(0)));
+        assert_eq!(bounds("(?<word>one|three)|(?i:x)"), (1, Some(5)));
+        assert_eq!(bounds(&format!("({})", ["ab", "c", "def", "g", "h", "i", "j", "k"].join("|"))), (1, Some(3)));
+        assert_eq!(bounds(""), (0, Some(0)));
+
+        let grapheme = MatcherBuilder::new().dot_matches_grapheme(true).build("a.").unwrap();
+        assert_eq!(grapheme.match_length_bounds(), (2, None));
+    }
+
+    #[test]
+    fn debug_tokens_test() {
+        let matcher = Matcher::new("abc(d|e|f).").unwrap();
+        assert_eq!(matcher.debug_tokens(), "0: RawText(\"abc\")\n1: OneOfText([d,e,f])\n2: WildCard");
+
+        let matcher = Matcher::new(r"^(?<n>a\.|b)x{2,3}?[a-z]*(?i:y)s?\b$").unwrap();
+        assert_eq!(
+            matcher.debug_tokens(),
+            "0: StartAnchor\n1: NamedGroup(n, [a\\.,b])\n2: Repeat(x, 2..=3, lazy)\n3: Repeat([a-z], 0..)\n\
+             4: IgnoreCase([y])\n5: Optional(s)\n6: WordBoundary\n7: EndAnchor"
+        );
+        assert_eq!(Matcher::new("").unwrap().debug_tokens(), "");
+    }
+
+    #[test]
+    fn is_fixed_width_test() {
+        let fixed = |pattern: &str| Matcher::new(pattern).unwrap().is_fixed_width();
+        assert!(fixed("abc."));
+        assert!(!fixed("(a|bb)"));
+        assert!(fixed("(ab|cd)[0-9]{3}é"));
+        assert!(!fixed("a*"));
+        assert!(!fixed("a+"));
+        assert!(!fixed("ab?"));
+        assert!(fixed("^(\\b)?x{2}$"));
+        assert!(fixed("(?<pair>..|\\d\\d)"));
+        assert!(fixed("(zero|four|five|nine|half|once|more|less)"));
+        assert!(!fixed("(zero|one|two|three|four|five|six|seven)"));
+        assert!(fixed(""));
+
+        let grapheme = MatcherBuilder::new().dot_matches_grapheme(true).build("a.").unwrap();
+        assert!(!grapheme.is_fixed_width());
+    }
+
+    #[test]
+    fn is_anchored_test() {
+        assert_eq!(Matcher::new("^abc").unwrap().is_anchored(), (true, false));
+        assert_eq!(Matcher::new("abc$").unwrap().is_anchored(), (false, true));
+        assert_eq!(Matcher::new("^abc$").unwrap().is_anchored(), (true, true));
+        assert_eq!(Matcher::new("abc").unwrap().is_anchored(), (false, false));
+        assert_eq!(Matcher::new("(^a|b)").unwrap().is_anchored(), (false, false));
+
+        // `find` only tries the start of the haystack, so it doesn't get any further.
+        let mut start = Matcher::new("^abc").unwrap();
+        assert_eq!(start.find("abcabc"), Some((0, 3)));
+        start.reset_progress();
+        assert_eq!(start.find("xabc"), None);
+        assert_eq!(start.furthest_byte(), 0);
+        assert_eq!(start.find_all("abcabc"), vec![(0, 3)]);
+        assert_eq!(start.find_from("abcabc", 3), None);
+
+        let mut both = Matcher::new("^abc$").unwrap();
+        assert_eq!(both.find("abc"), Some((0, 3)));
+        assert_eq!(both.find("abcd"), None);
+        assert_eq!(Matcher::new("abc$").unwrap().find("abcabc"), Some((3, 6)));
+        assert_eq!(Matcher::new("^").unwrap().find_all("ab"), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn concat_test() {
+        let mut prefix = Matcher::new("abc").unwrap();
+        assert!(prefix.is_match("abc"));
+        let suffix = Matcher::new("(d|e).").unwrap();
+
+        let mut joined = prefix.concat(suffix);
+        assert_eq!(joined.tokens_matched(), 0);
+        assert_eq!(joined.text, "abc(d|e).");
+        assert_eq!(joined.tokens, vec![MatcherToken::RawText(Cow::Borrowed("abc")), one_of(&["d", "e"]), MatcherToken::WildCard]);
+        assert!(joined.is_match("abcdx"));
+        assert!(joined.is_match("abce💪"));
+        assert!(!joined.is_match("abc"));
+
+        // The text is written out from the tokens, so it still means the same thing.
+        let either = Matcher::new("a|b").unwrap().concat(Matcher::new("c").unwrap());
+        assert_eq!(either.text, "(a|b)c");
+        assert_eq!(Matcher::new(&either.text).unwrap().tokens, either.tokens);
+    }
+
+    #[test]
+    fn match_indices_test() {
+        let mut matcher = Matcher::new("^ab(c|d)x?e$").unwrap();
+        assert_eq!(
+            matcher.match_indices("abde"),
+            vec![
+                (&MatcherToken::StartAnchor, 0..0),
+                (&MatcherToken::RawText(Cow::Borrowed("ab")), 0..2),
+                (&one_of(&["c", "d"]), 2..3),
+                (&MatcherToken::Optional(Box::new(MatcherToken::RawText(Cow::Borrowed("x")))), 3..3),
+                (&MatcherToken::RawText(Cow::Borrowed("e")), 3..4),
+                (&MatcherToken::EndAnchor, 4..4),
+            ]
+        );
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        // Like `match_string`, it stops where the tokens stopped matching.
+        let mut partial = Matcher::new("💪b.").unwrap();
+        assert_eq!(
+            partial.match_indices("💪bc"),
+            vec![(&MatcherToken::RawText(Cow::Borrowed("💪b")), 0..5), (&MatcherToken::WildCard, 5..6)]
+        );
+        assert_eq!(partial.match_indices("💪x"), vec![]);
+    }
+
+    #[test]
+    fn match_from_test() {
+        let mut matcher = Matcher::new("[0-9]+(px|em)").unwrap();
+        let css = "width: 12px;";
+        let digits = MatcherToken::Repeat {
+            inner: Box::new(MatcherToken::CharClass(CharSet::shorthand('d').unwrap())),
+            min: 1,
+            max: None,
+            greedy: true,
+        };
+        assert_eq!(matcher.match_from(css, 7), vec![(&digits, "12"), (&one_of(&["px", "em"]), "px")]);
+        assert_eq!(matcher.match_from(css, 8).len(), 2);
+        assert_eq!(matcher.match_from(css, 0), vec![]);
+        assert_eq!(matcher.match_from(css, 100), vec![]);
+
+        // An offset inside a character matches nothing, rather than panicking.
+        let mut any = Matcher::new(".").unwrap();
+        assert_eq!(any.match_from("💪a", 4), vec![(&MatcherToken::WildCard, "a")]);
+        assert_eq!(any.match_from("💪a", 1), vec![]);
+
+        let mut start = Matcher::new("^a").unwrap();
+        assert_eq!(start.match_from("aa", 1), vec![]);
+    }
+
+    #[test]
+    fn match_with_choices_test() {
+        let mut matcher = Matcher::new("(cat|dog)s?.").unwrap();
+        let animals = one_of(&["cat", "dog"]);
+        let s = MatcherToken::Optional(Box::new(MatcherToken::RawText(Cow::Borrowed("s"))));
+        assert_eq!(
+            matcher.match_with_choices("dogs!"),
+            vec![(&animals, "dog", Some(1)), (&s, "s", None), (&MatcherToken::WildCard, "!", None)]
+        );
+        assert_eq!(matcher.match_with_choices("cat!")[0], (&animals, "cat", Some(0)));
+
+        // The choice is the option the search used, even if it had to backtrack.
+        let mut backtrack = Matcher::new("(a|ab|abc)c").unwrap();
+        assert_eq!(backtrack.match_with_choices("abc")[0].2, Some(1));
+
+        let mut words = Matcher::new("(zero|one|two|three|four|five|six|seven)").unwrap();
+        assert_eq!(words.match_with_choices("six")[0].2, Some(6));
+        let mut named = Matcher::new("(?<bit>0|1)").unwrap();
+        assert_eq!(named.match_with_choices("1")[0].2, Some(1));
+    }
+
+    #[test]
+    fn match_with_alternatives_test() {
+        let mut matcher = Matcher::new("x(a[0-9]|(?<y>y+)|b.)").unwrap();
+        let matched = matcher.match_with_alternatives("xb!");
+        assert_eq!(matched.len(), 2);
+        assert_eq!(matched[0].2, None);
+        let Some(Alternative { index, tokens }) = &matched[1].2 else { panic!("the group should have an alternative") };
+        assert_eq!(*index, 2);
+        assert_eq!(tokens, &[MatcherToken::RawText(Cow::Borrowed("b")), MatcherToken::WildCard]);
+
+        // An option's tokens can be matched on their own, like a whole pattern.
+        let mut matcher = Matcher::new("x(a[0-9]|(?<y>y+)|b.)").unwrap();
+        let matched = matcher.match_with_alternatives("xyy");
+        let alternative = matched[1].2.as_ref().unwrap();
+        assert_eq!(alternative.index, 1);
+        let mut option = Matcher::from_tokens(alternative.tokens.to_vec());
+        assert_eq!(option.to_string(), "(?<y>y+)");
+        assert_eq!(option.match_string("yyy")[0].1, "yyy");
+
+        let mut words = Matcher::new("(zero|one|two|three|four|five|six|seven)").unwrap();
+        assert_eq!(words.match_with_alternatives("six")[0].2, None);
+    }
+
+    #[test]
+    fn match_detailed_test() {
+        let mut matcher = Matcher::new("abc(d|e|f).").unwrap();
+        assert_eq!(
+            matcher.match_detailed("abcdxyz"),
+            Ok(vec![
+                (&MatcherToken::RawText(Cow::Borrowed("abc")), "abc"),
+                (&one_of(&["d", "e", "f"]), "d"),
+                (&MatcherToken::WildCard, "x"),
+            ])
+        );
+        assert_eq!(matcher.match_detailed("abcx"), Err(3));
+        assert_eq!(matcher.match_detailed("abcd"), Err(4));
+        assert_eq!(matcher.match_detailed("xyz"), Err(0));
+
+        let mut unicode = Matcher::new("💪 [0-9]+").unwrap();
+        let text = "💪 x";
+        let at = unicode.match_detailed(text).unwrap_err();
+        assert_eq!(&text[at..], "x");
+    }
+
+    #[test]
+    fn matched_parts_test() {
+        let mut matcher = Matcher::new(r"(?<key>[a-z]+)=\d*;?").unwrap();
+        let string = "size=12;rest";
+        let parts = matcher.matched_parts(string);
+        assert_eq!(parts, vec!["size", "=", "12", ";"]);
+        assert_eq!(parts.concat(), matcher.longest_prefix(string));
+        assert_eq!(matcher.tokens_matched(), 4);
+
+        assert_eq!(matcher.matched_parts("size=").concat(), "size=");
+        assert_eq!(matcher.matched_parts("size"), vec!["size"]);
+        assert_eq!(matcher.matched_parts(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn wildcard_captures_test() {
+        let mut template = Matcher::new("abc.def.").unwrap();
+        assert_eq!(template.wildcard_captures("abcXdef💪"), vec!["X", "💪"]);
+        assert_eq!(template.wildcard_captures("abcXdeg!"), vec!["X"]);
+        assert_eq!(template.wildcard_captures("xyz"), Vec::<&str>::new());
+
+        let mut nested = Matcher::new("(.)=.+;.").unwrap();
+        assert_eq!(nested.wildcard_captures("a=bc;d"), vec!["d"]);
+    }
+
+    #[test]
+    fn match_string_ref_test() {
+        let mut matcher = Matcher::new("(cat|dog)s?!").unwrap();
+        let mut tracked = matcher.clone();
+        let expected = tracked.match_string("dogs!?");
+        assert_eq!(matcher.match_string_ref("dogs!?"), expected);
+        assert_eq!(matcher.match_string_ref("cow").len(), 0);
+        assert_eq!(matcher.tokens_matched(), 0);
+        assert_eq!(matcher.furthest_byte(), 0);
+
+        let shared = Arc::new(matcher.clone());
+        std::thread::scope(|scope| {
+            for string in ["cat!", "dogs!", "cats"] {
+                let shared = Arc::clone(&shared);
+                scope.spawn(move || {
+                    let parts: String = shared.match_string_ref(string).into_iter().map(|(_, part)| part).collect();
+                    assert_eq!(parts, string);
+                });
+            }
+        });
+
+        matcher.match_string("cats");
+        assert_eq!(matcher.tokens_matched(), 2);
+    }
+
+    #[test]
+    fn match_string_budgeted_test() {
+        // Each of the three tokens is tried once.
+        let mut matcher = Matcher::new("a.c").unwrap();
+        assert_eq!(matcher.match_string_budgeted("abc", 3).unwrap().len(), 3);
+        assert_eq!(matcher.match_string_budgeted("abc", 2), Err(BudgetExceeded { max_steps: 2 }));
+        assert_eq!(matcher.match_string_budgeted("xbc", 1), Ok(vec![]));
+
+        // Without a `b`, this tries every way of splitting up the `a`s.
+        let mut backtracking = Matcher::new("(a*)*b").unwrap();
+        let string = "a".repeat(25);
+        assert_eq!(backtracking.match_string_budgeted(&string, 1000), Err(BudgetExceeded { max_steps: 1000 }));
+        assert!(backtracking.furthest_byte() > 0);
+        assert_eq!(backtracking.match_string_budgeted("aab", 1000).unwrap().len(), 2);
+        assert_eq!(BudgetExceeded { max_steps: 10 }.to_string(), "gave up matching after 10 steps");
+    }
+
+    #[test]
+    fn evaluate_test() {
+        let mut matcher = Matcher::new("ab(c|d)e").unwrap();
+        let result = matcher.evaluate("abdef");
+        assert_eq!(
+            result.tokens,
+            vec![(&MatcherToken::RawText(Cow::Borrowed("ab")), "ab"), (&one_of(&["c", "d"]), "d"), (&MatcherToken::RawText(Cow::Borrowed("e")), "e")]
+        );
+        assert_eq!(result.consumed, 4);
+        assert!(result.complete);
+
+        let result = matcher.evaluate("abx");
+        assert_eq!(result.tokens, vec![(&MatcherToken::RawText(Cow::Borrowed("ab")), "ab")]);
+        assert_eq!(result.consumed, 2);
+        assert!(!result.complete);
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        // Matching nothing can still be complete.
+        assert!(Matcher::new("x*").unwrap().evaluate("abc").complete);
+    }
+
+    #[test]
+    fn longest_prefix_test() {
+        let mut matcher = Matcher::new("abc(d|e)[0-9]+").unwrap();
+        assert_eq!(matcher.longest_prefix("abcd42"), "abcd42");
+        assert_eq!(matcher.longest_prefix("abcd42 and more"), "abcd42");
+        assert_eq!(matcher.longest_prefix("abcx"), "abc");
+        assert_eq!(matcher.longest_prefix("abe"), "");
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        let mut unicode = Matcher::new("💪+x").unwrap();
+        assert_eq!(unicode.longest_prefix("💪💪y"), "💪💪");
+    }
+
+    #[test]
+    fn full_match_test() {
+        let mut matcher = Matcher::new("abc(d|e|f).").unwrap();
+        assert_eq!(
+            matcher.full_match("abcdx"),
+            Some(vec![
+                (&MatcherToken::RawText(Cow::Borrowed("abc")), "abc"),
+                (&one_of(&["d", "e", "f"]), "d"),
+                (&MatcherToken::WildCard, "x"),
+            ])
+        );
+        assert_eq!(matcher.full_match("abcdxy"), None);
+        assert_eq!(matcher.full_match("abcd"), None);
+        assert_eq!(matcher.tokens_matched(), 3);
+
+        let mut backtrack = Matcher::new("(a|ab)").unwrap();
+        assert_eq!(backtrack.match_string("ab")[0].1, "a");
+        assert_eq!(backtrack.full_match("ab"), Some(vec![(&one_of(&["a", "ab"]), "ab")]));
+
+        assert_eq!(Matcher::new("x*").unwrap().full_match(""), Some(vec![]));
+    }
+
+    #[test]
+    fn multi_matcher_test() {
+        let mut lexer = Matcher::new_multi(&["(if|else)", r"[a-z_]+", r"\d+", r"\s+"]).unwrap();
+        assert_eq!(lexer.match_any("if x"), Some((0, 2)));
+        assert_eq!(lexer.match_any("iffy = 1"), Some((0, 2)));
+        assert_eq!(lexer.match_any("x = 1"), Some((1, 1)));
+        assert_eq!(lexer.match_any("123 + x"), Some((2, 3)));
+        assert_eq!(lexer.match_any("  if"), Some((3, 2)));
+        assert_eq!(lexer.match_any("= 1"), None);
+        assert_eq!(lexer.match_any(""), None);
+
+        // With the longest match winning, `iffy` is a name rather than `if`,
+        // but a tie still goes to the first pattern.
+        assert_eq!(lexer.match_longest("iffy = 1"), Some((1, 4)));
+        assert_eq!(lexer.match_longest("if x"), Some((0, 2)));
+        assert_eq!(lexer.match_longest("123 + x"), Some((2, 3)));
+        assert_eq!(lexer.match_longest("= 1"), None);
+
+        assert_eq!(Matcher::new_multi(&["a", "(b", "c)"]), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+        assert_eq!(Matcher::new_multi(&[]).unwrap().match_any("a"), None);
+    }
+
+    #[test]
+    fn bytes_matcher_test() {
+        let header: &[u8] = b"GIF89a\x0a\x00\xff\xfe rest";
+        let gif = BytesMatcher::new(b"GIF8(7|9)a..[^a-z]+").unwrap();
+        let parts = gif.match_bytes(header).unwrap();
+        let parts: Vec<&[u8]> = parts.into_iter().map(|(_, part)| part).collect();
+        assert_eq!(parts, vec![&b"GIF8"[..], b"9", b"a", b"\x0a", b"\x00", b"\xff\xfe "]);
+        assert_eq!(gif.match_bytes(b"GIF88a\x00\x00!"), None);
+
+        // It backtracks like `match_string`, and `.` matches any byte, even `\n`.
+        let any = BytesMatcher::new(b"(a|ab)c.*d").unwrap();
+        assert!(any.is_match(b"abc\n\x80d"));
+        assert!(!any.is_match(b"abc\n\x80"));
+        assert_eq!(any.find(b"\xc3xabcd!"), Some((2, 6)));
+        assert_eq!(any.find(b"\xc3"), None);
+
+        let words = BytesMatcher::new(br"^\b(cat|dog|fish|bird|cow|pig|hen|ox)\b!?$").unwrap();
+        assert!(matches!(words.tokens[2], BytesToken::OneOfWords(_)));
+        assert!(words.is_match(b"hen"));
+        assert!(!words.is_match(b"hens"));
+
+        assert_eq!(BytesMatcher::new(b"a\xffb"), Err(MatcherParseError::InvalidUtf8 { at: 1 }));
+        assert_eq!(BytesMatcher::new(b"(a"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+    }
+
+    #[test]
+    fn full_matcher_test() {
+        let mut matcher = FullMatcher::new("abc").unwrap();
+        assert_eq!(matcher.match_string("abc"), Some(vec![(&MatcherToken::RawText(Cow::Borrowed("abc")), "abc")]));
+        assert_eq!(matcher.match_string("abcd"), None);
+        assert_eq!(matcher.match_string("ab"), None);
+        assert!(matcher.is_match("abc"));
+        assert!(!matcher.is_match("abcd"));
+
+        // The same pattern as a `Matcher` still matches the start of the string.
+        let mut prefix = Matcher::new("abc").unwrap();
+        assert_eq!(prefix.match_string("abcd"), vec![(&MatcherToken::RawText(Cow::Borrowed("abc")), "abc")]);
+
+        let mut from_matcher = FullMatcher::from(Matcher::new_case_insensitive("a+").unwrap());
+        assert_eq!(from_matcher.match_string("aAa").map(|answer| answer.len()), Some(1));
+        assert_eq!(FullMatcher::new("(a"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+    }
+
+    #[test]
+    fn is_prefix_match_test() {
+        let mut matcher = Matcher::new("abc").unwrap();
+        assert!(matcher.is_prefix_match("abcd"));
+        assert!(!matcher.is_match("abcd"));
+        assert!(matcher.is_prefix_match("abc"));
+        assert!(matcher.is_match("abc"));
+        assert!(!matcher.is_prefix_match("ab"));
+        assert!(!matcher.is_prefix_match("xabc"));
+        assert_eq!(matcher.tokens_matched(), 1);
+
+        let mut anchored = Matcher::new("a$").unwrap();
+        assert!(!anchored.is_prefix_match("ab"));
+        assert!(Matcher::new("x*").unwrap().is_prefix_match("abc"));
+    }
+
+    #[test]
+    fn accepts_same_test() {
+        let samples = ["", "a", "b", "c", "ab", "ba", "aa", "💪"];
+        let mut group = Matcher::new("(a|b)").unwrap();
+        let mut class = Matcher::new("[ab]").unwrap();
+        assert!(Matcher::accepts_same(&mut group, &mut class, &samples));
+
+        let mut repeated = Matcher::new("[ab]+").unwrap();
+        assert!(!Matcher::accepts_same(&mut group, &mut repeated, &samples));
+        // It can only tell them apart with a sample they disagree about.
+        assert!(Matcher::accepts_same(&mut group, &mut repeated, &["a", "b", "c"]));
+        assert!(Matcher::accepts_same(&mut group, &mut repeated, &[]));
+    }
+
+    #[test]
+    fn match_len_test() {
+        let mut matcher = Matcher::new(r"[a-z]+@[a-z]+\.(com|org)").unwrap();
+        assert_eq!(matcher.match_len("me@example.com"), Some(14));
+        assert_eq!(matcher.match_len("me@example.org and more"), Some(14));
+        assert_eq!(matcher.match_len("me@example"), None);
+        assert_eq!(matcher.match_len(""), None);
+
+        let (len, allocations) = count_allocations(|| matcher.match_len("me@example.com"));
+        assert_eq!(len, Some(14));
+        assert_eq!(allocations, 0);
+
+        // It keeps track of how far it got, like the other ways of matching.
+        let mut partial = Matcher::new(r"[a-z]+@[a-z]+\.(com|org)").unwrap();
+        assert_eq!(partial.match_len("me@example.net"), None);
+        assert_eq!(partial.tokens_matched(), 4);
+        assert_eq!(partial.furthest_byte(), 11);
+        let mut parsed = Matcher::new(r"[a-z]+@[a-z]+\.(com|org)").unwrap();
+        parsed.is_match("me@example.net");
+        assert_eq!((parsed.tokens_matched(), parsed.furthest_byte()), (4, 11));
+
+        // It matches like `match_string`, including backtracking.
+        let mut backtrack = Matcher::new("(a|ab)c.*d").unwrap();
+        assert_eq!(backtrack.match_len("abcxdy"), Some(5));
+        assert_eq!(backtrack.match_string("abcxdy").len(), 4);
+        assert_eq!(Matcher::new("x*").unwrap().match_len("abc"), Some(0));
+    }
+
+    /// This times `match_len` against `match_string` on an email-like pattern.
+    /// Run it with `cargo test --release -- --ignored --nocapture match_len_benchmark`.
+    #[test]
+    #[ignore]
+    fn match_len_benchmark() {
+        let mut matcher = Matcher::new(r"[a-z0-9_]+(\.[a-z0-9_]+)*@[a-z]+\.(com|org|net)").unwrap();
+        let email = "first.last_name@example.org";
+        let iterations = 100_000;
+
+        let started = Instant::now();
+        let (_, allocations) = count_allocations(|| {
+            for _ in 0..iterations {
+                assert_eq!(matcher.match_string(email).len(), 6);
+            }
+        });
+        println!("match_string: {:?} per match, {allocations} allocations", started.elapsed() / iterations);
+
+        let started = Instant::now();
+        let (_, allocations) = count_allocations(|| {
+            for _ in 0..iterations {
+                assert_eq!(matcher.match_len(email), Some(email.len()));
+            }
+        });
+        println!("match_len: {:?} per match, {allocations} allocations", started.elapsed() / iterations);
+    }
+
+    #[test]
+    fn case_insensitive_test() {
+        let mut matcher = Matcher::new_case_insensitive("(cat|dog)").unwrap();
+        assert!(matcher.is_match("CAT"));
+        assert!(matcher.is_match("dOg"));
+        assert_eq!(matcher.match_string("CAT"), vec![(&one_of(&["cat", "dog"]), "CAT")]);
+
+        let mut raw = Matcher::new_case_insensitive("abc").unwrap();
+        assert!(raw.is_match("ABC"));
+        assert!(!Matcher::new("abc").unwrap().is_match("ABC"));
+
+        let mut class = Matcher::new_case_insensitive("[a-c]+[^x]").unwrap();
+        assert!(class.is_match("BcAy"));
+        assert!(!class.is_match("BcAX"));
+
+        // Only ASCII letters are folded.
+        let mut accent = Matcher::new_case_insensitive("é").unwrap();
+        assert!(!accent.is_match("É"));
+    }
+
+    #[test]
+    fn dot_matches_newline_test() {
+        let mut matcher = Matcher::new("a.b").unwrap();
+        assert!(matcher.is_match("a-b"));
+        assert!(!matcher.is_match("a\nb"));
+        assert!(matcher.is_match("a\rb"));
+
+        let mut lines = Matcher::new("<.*>").unwrap();
+        assert_eq!(lines.find("<a\nb> <c>"), Some((6, 9)));
+
+        let options = MatchOptions { dot_matches_newline: true, ..MatchOptions::default() };
+        let mut matcher = Matcher::with_options("a.b", options).unwrap();
+        assert!(matcher.is_match("a\nb"));
+        let mut lines = Matcher::with_options("<.*>", options).unwrap();
+        assert_eq!(lines.find("<a\nb> <c>"), Some((0, 9)));
+
+        // Character classes aren't changed by it.
+        assert!(Matcher::new("a[^x]b").unwrap().is_match("a\nb"));
+    }
+
+    #[test]
+    fn builder_test() {
+        let builder = MatcherBuilder::new().case_insensitive(true).dot_matches_newline(true);
+        let mut matcher = builder.build("<a.b>").unwrap();
+        assert!(matcher.is_match("<A\nB>"));
+        assert!(matcher.is_match("<a-b>"));
+        assert_eq!(builder.build("(a"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+
+        // Turning an option off again, or not choosing it, is the same as `Matcher::new`.
+        let mut plain = builder.case_insensitive(false).dot_matches_newline(false).build("<a.b>").unwrap();
+        assert_eq!(plain, Matcher::new("<a.b>").unwrap());
+        assert!(!plain.is_match("<A-B>"));
+        assert!(!plain.is_match("<a\nb>"));
+        assert_eq!(MatcherBuilder::new().build("a.b").unwrap(), Matcher::new("a.b").unwrap());
+
+        let mut insensitive = MatcherBuilder::new().case_insensitive(true).build("a.b").unwrap();
+        assert!(insensitive.is_match("A-B"));
+        assert!(!insensitive.is_match("A\nB"));
+    }
+
+    #[test]
+    fn longest_alternative_first_test() {
+        let mut first = Matcher::new("(a|ab)").unwrap();
+        assert_eq!(first.match_with_choices("ab")[0].1, "a");
+
+        let builder = MatcherBuilder::new().longest_alternative_first(true);
+        let mut longest = builder.build("(a|ab)").unwrap();
+        assert_eq!(longest.match_with_choices("ab")[0].1, "ab");
+        assert_eq!(longest.match_with_choices("ab")[0].2, Some(0));
+        assert_eq!(longest.match_with_choices("a")[0].2, Some(1));
+        assert_eq!(longest.text, "(a|ab)");
+        assert_eq!(longest.to_string(), "(ab|a)");
+
+        // Options inside other groups and quantifiers are sorted too, and ones
+        // of the same length keep their order.
+        let nested = builder.build("x(y|(b|bc)+|z)?").unwrap();
+        assert_eq!(nested.to_string(), "x((bc|b)+|y|z)?");
+        assert_eq!(builder.build("(?<n>1|12)").unwrap().to_string(), "(?<n>12|1)");
+        let mut nested = builder.build("x(y|(b|bc)+)").unwrap();
+        assert_eq!(nested.match_string("xbcb")[1].1, "bcb");
+
+        let mut words = builder.build("(a|b|c|d|e|f|g|h|hi)").unwrap();
+        assert!(matches!(words.tokens[0], MatcherToken::OneOfWords(_)));
+        assert_eq!(words.match_string("hi")[0].1, "hi");
+        assert_eq!(Matcher::new("(a|b|c|d|e|f|g|h|hi)").unwrap().match_string("hi")[0].1, "h");
+    }
+
+    #[test]
+    fn dot_matches_grapheme_test() {
+        let thumbs_up = "👍🏽";
+        let family = "👨\u{200D}👩\u{200D}👧";
+        let accented = "e\u{0301}";
+        let flag = "🇬🇧";
+
+        let mut one = Matcher::new("^.$").unwrap();
+        for grapheme in [thumbs_up, family, accented, flag] {
+            assert!(!one.is_match(grapheme), "{grapheme:?}");
+        }
+
+        let builder = MatcherBuilder::new().dot_matches_grapheme(true);
+        let mut one = builder.build("^.$").unwrap();
+        for grapheme in [thumbs_up, family, accented, flag, "a", "💪", "\r\n"] {
+            assert!(one.is_match(grapheme), "{grapheme:?}");
+        }
+        assert!(!one.is_match("ab"));
+        assert!(!one.is_match("\n"));
+        assert!(!one.is_match("🇬🇧🇫"));
+
+        let mut each = builder.build(".").unwrap();
+        let text = format!("a{thumbs_up}{flag}{flag}{accented}\u{200D}");
+        let found: Vec<_> = each.find_all(&text).into_iter().map(|(start, end)| &text[start..end]).collect();
+        assert_eq!(found, vec!["a", thumbs_up, flag, flag, "e\u{0301}\u{200D}"]);
+    }
+
+    #[cfg(feature = "unicode-segmentation")]
+    #[test]
+    fn unicode_segmentation_test() {
+        let flag = "🇫🇷";
+        let accented = "a\u{0308}";
+        // These are one grapheme each, but not ones the approximation without the feature knows about.
+        let hangul = "\u{1100}\u{1161}\u{11A8}";
+        let devanagari = "\u{0915}\u{093F}";
+
+        let mut one = MatcherBuilder::new().dot_matches_grapheme(true).build("^.$").unwrap();
+        for grapheme in [flag, accented, hangul, devanagari] {
+            assert!(one.is_match(grapheme), "{grapheme:?}");
+        }
+        let mut each = MatcherBuilder::new().dot_matches_grapheme(true).build(".").unwrap();
+        let text = format!("{flag}{accented}b");
+        let found: Vec<_> = each.find_all(&text).into_iter().map(|(start, end)| &text[start..end]).collect();
+        assert_eq!(found, vec![flag, accented, "b"]);
+
+        // Without `dot_matches_grapheme`, a `.` is still one `char`.
+        assert!(!Matcher::new("^.$").unwrap().is_match(accented));
+        assert!(Matcher::new("^..$").unwrap().is_match(accented));
+    }
+
+    #[test]
+    fn wildcard_test() {
+        let mut digits = Matcher::with_wildcard("#..-.", |c| c.is_ascii_digit()).unwrap();
+        assert!(digits.is_match("#12-3"));
+        assert!(!digits.is_match("#1a-3"));
+        assert!(!digits.is_match("#١٢-3"));
+        assert_eq!(digits.find("#ab-1 #42-7"), Some((6, 11)));
+
+        // Other tokens aren't changed by it.
+        let mut class = Matcher::with_wildcard("[a-z].", |c| c.is_ascii_digit()).unwrap();
+        assert!(class.is_match("x1"));
+        assert!(!class.is_match("xy"));
+
+        let builder = MatcherBuilder::new().wildcard(char::is_alphabetic).dot_matches_grapheme(true);
+        let mut letters = builder.build("^.+$").unwrap();
+        assert!(letters.is_match("e\u{0301}é"));
+        assert!(!letters.is_match("e1"));
+        assert_eq!(letters.clone(), letters);
+        assert_ne!(letters, MatcherBuilder::new().dot_matches_grapheme(true).build("^.+$").unwrap());
+
+        let mut newline = MatcherBuilder::new().wildcard(char::is_whitespace).build(".").unwrap();
+        assert!(newline.is_match(" "));
+        assert!(!newline.is_match("\n"));
+    }
+
+    #[test]
+    fn whole_words_test() {
+        let mut anywhere = Matcher::new("cat").unwrap();
+        assert_eq!(anywhere.find("the cat"), Some((4, 7)));
+        assert_eq!(anywhere.find("category"), Some((0, 3)));
+
+        let builder = MatcherBuilder::new().whole_words(true);
+        let mut words = builder.build("cat").unwrap();
+        assert_eq!(words.find("the cat"), Some((4, 7)));
+        assert_eq!(words.find("category"), None);
+        assert_eq!(words.find("bobcat"), None);
+        assert_eq!(words.find("category, cat."), Some((10, 13)));
+        assert_eq!(words.find_all("cat cats cat_ cat"), vec![(0, 3), (14, 17)]);
+        assert_eq!(words.count_matches("concatenate"), 0);
+        assert_eq!(words.replace_all("cat category", "dog"), "dog category");
+        assert_eq!(words.find_all_overlapping("cat"), vec![(0, 3)]);
+        assert_eq!(words.compile().find("category cat"), Some((9, 12)));
+
+        // A match is only skipped if it doesn't fit, so a later one can still be found.
+        let mut digits = builder.build(r"\d+").unwrap();
+        assert_eq!(digits.find("a1 22"), Some((3, 5)));
+        // It doesn't change matching at the start of a string.
+        assert_eq!(words.match_len("category"), Some(3));
+    }
+
+    #[test]
+    fn trace_test() {
+        thread_local! {
+            static STEPS: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+        }
+        fn record(token: &MatcherToken, position: usize, matched: Option<&str>) {
+            STEPS.with_borrow_mut(|steps| steps.push(format!("{token} {position} {matched:?}")));
+        }
+
+        let mut matcher = MatcherBuilder::new().trace(record).build("(a|ab)c").unwrap();
+        assert_eq!(matcher.match_string("abc").len(), 2);
+        assert_eq!(
+            STEPS.take(),
+            vec![
+                "a 0 Some(\"a\")",
+                "(a|ab) 0 Some(\"a\")",
+                "c 1 None",
+                "ab 0 Some(\"ab\")",
+                "(a|ab) 0 Some(\"ab\")",
+                "c 2 Some(\"c\")",
+            ]
+        );
+
+        // Without a trace, nothing is recorded.
+        Matcher::new("(a|ab)c").unwrap().match_string("abc");
+        assert_eq!(STEPS.take(), Vec::<String>::new());
+
+        let printing = MatcherBuilder::new().trace(trace_to_stderr).build("a").unwrap();
+        assert_ne!(printing, MatcherBuilder::new().trace(record).build("a").unwrap());
+        assert_eq!(printing.match_string_ref("a").len(), 1);
+    }
+
+    #[test]
+    fn max_alternatives_test() {
+        let builder = MatcherBuilder::new().max_alternatives(3);
+        assert!(builder.build("(a|b|c)").is_ok());
+        assert_eq!(builder.build("(a|b|c|d)"), Err(MatcherParseError::TooManyAlternatives { at: 6 }));
+        assert_eq!(builder.build("x(a|(b|c|d|e)|f)"), Err(MatcherParseError::TooManyAlternatives { at: 10 }));
+        assert_eq!(builder.build("a|b|c|d"), Err(MatcherParseError::TooManyAlternatives { at: 5 }));
+        assert!(builder.build("a|b|(c|d|e)").is_ok());
+        assert!(builder.build(r"[a|b|c|d]\|(x|\||y)").is_ok());
+
+        assert_eq!(Matcher::with_max_alternatives("a|b", 1), Err(MatcherParseError::TooManyAlternatives { at: 1 }));
+        assert_eq!(Matcher::with_max_alternatives("(a)", 0), Err(MatcherParseError::TooManyAlternatives { at: 0 }));
+        assert!(Matcher::with_max_alternatives("abc", 0).is_ok());
+        assert!(Matcher::new(&format!("({})", ["a"; 1000].join("|"))).is_ok());
+        assert_eq!(MatcherParseError::TooManyAlternatives { at: 6 }.to_string(), "too many alternatives at byte 6");
+    }
+
+    #[test]
+    fn render_error_test() {
+        let pattern = "ab(cd|e";
+        let error = Matcher::new(pattern).unwrap_err();
+        assert_eq!(render_error(pattern, &error), "error: unbalanced parenthesis at byte 2\n  |\n  | ab(cd|e\n  |   ^\n");
+
+        let rendered = render_error("é)", &Matcher::new("é)").unwrap_err());
+        assert!(rendered.ends_with("  | é)\n  |  ^\n"));
+
+        // Only the line the error is on is shown, and the end of the pattern is after its last character.
+        let rendered = render_error("a\nb\\", &MatcherParseError::TrailingBackslash { at: 3 });
+        assert!(rendered.ends_with("  | b\\\n  |  ^\n"));
+        let rendered = render_error("a{", &MatcherParseError::InvalidRepetition { at: 2 });
+        assert!(rendered.ends_with("  | a{\n  |   ^\n"));
+    }
+
+    #[test]
+    fn trim_whitespace_test() {
+        let trimming = MatcherBuilder::new().trim_whitespace(true);
+        assert_eq!(trimming.build("  abc  ").unwrap(), Matcher::new("abc").unwrap());
+        assert_eq!(trimming.build("\tx y\n").unwrap(), Matcher::new("x y").unwrap());
+        assert_eq!(trimming.build("   ").unwrap(), Matcher::new("").unwrap());
+        assert_eq!(trimming.build("  a(b"), Err(MatcherParseError::UnbalancedParen { at: 1 }));
+
+        // Without the flag, the whitespace is part of the text to match.
+        let mut spaced = MatcherBuilder::new().build("  abc  ").unwrap();
+        assert_ne!(spaced, Matcher::new("abc").unwrap());
+        assert!(!spaced.is_match("abc"));
+        assert!(spaced.is_match("  abc  "));
+    }
+
+    #[test]
+    fn captures_test() {
+        let mut phone = Matcher::new(r"(?<area>\d{3})-(?<number>\d{4})").unwrap();
+        let captures = phone.captures("call 555-1234 now").unwrap();
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures["area"], "555");
+        assert_eq!(captures["number"], "1234");
+        assert_eq!(phone.captures("call me"), None);
+
+        // Unnamed groups, and named ones which weren't used, aren't in the map.
+        let mut matcher = Matcher::new("(a|b)((?<x>x)|(?<y>y))").unwrap();
+        let captures = matcher.captures("ay").unwrap();
+        assert_eq!(captures.len(), 1);
+        assert_eq!(captures["y"], "y");
+
+        // A group which was backtracked out of is forgotten.
+        let mut backtrack = Matcher::new("((?<first>ab)c|a(?<second>bd))").unwrap();
+        let captures = backtrack.captures("abd").unwrap();
+        assert_eq!(captures.len(), 1);
+        assert_eq!(captures["second"], "bd");
+
+        let mut repeated = Matcher::new(r"(?<digit>\d)+").unwrap();
+        assert_eq!(repeated.captures("123").unwrap()["digit"], "3");
+        assert_eq!(repeated.to_string(), r"(?<digit>\d)+");
+
+        assert_eq!(Matcher::new("(?<>a)"), Err(MatcherParseError::InvalidGroupName { at: 0 }));
+        assert_eq!(Matcher::new("a(?<name"), Err(MatcherParseError::InvalidGroupName { at: 1 }));
+        assert_eq!(Matcher::new("(?<a b>c)"), Err(MatcherParseError::InvalidGroupName { at: 0 }));
+        assert_eq!(Matcher::new("(?<name>a"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+    }
+
+    #[test]
+    fn ignore_case_group_test() {
+        let mut pets = Matcher::new("(?i:cat|dog) (food|toys)").unwrap();
+        assert!(pets.is_match("CAT food"));
+        assert!(pets.is_match("Dog toys"));
+        assert!(!pets.is_match("cat FOOD"));
+        assert_eq!(pets.to_string(), "(?i:cat|dog) (food|toys)");
+        assert_eq!(pets.match_string("dOg toys").len(), 3);
+
+        // Case is ignored again if the rest of the pattern backtracks into the group.
+        let mut backtrack = Matcher::new("(?i:a+)a").unwrap();
+        assert_eq!(backtrack.find("AAa"), Some((0, 3)));
+        assert_eq!(backtrack.find("AAA"), None);
+
+        let mut inside = Matcher::new("(?i:[a-c]x(y|z))w").unwrap();
+        assert!(inside.is_match("BXZw"));
+        assert!(!inside.is_match("BXZW"));
+
+        // A case-insensitive matcher still ignores case outside of the group.
+        let mut everywhere = Matcher::new_case_insensitive("(?i:a)b").unwrap();
+        assert!(everywhere.is_match("AB"));
+
+        assert_eq!(Matcher::new("(?i:cat|dog"), Err(MatcherParseError::UnbalancedParen { at: 0 }));
+        assert_eq!(Matcher::new("x(?i:)"), Err(MatcherParseError::EmptyGroup { at: 1 }));
+    }
+
+    #[test]
+    fn new_in_test() {
+        let arena = Arena::new();
+        let config = [String::from("ab+c"), String::from("(x|y)z"), String::from("")];
+        let mut matchers: Vec<Matcher> = config.iter().map(|text| Matcher::new_in(text, &arena).unwrap()).collect();
+        drop(config);
+
+        assert!(matchers[0].is_match("abbc"));
+        assert_eq!(matchers[1].find("wwyz"), Some((2, 4)));
+        assert_eq!(matchers[2].find("anything"), Some((0, 0)));
+        assert_eq!(matchers[1].to_string(), "(x|y)z");
+
+        let first = arena.alloc("abc");
+        let second = arena.alloc("abc");
+        assert!(!core::ptr::eq(first, second));
+        assert_eq!(Matcher::new_in("a(b", &arena), Err(MatcherParseError::UnbalancedParen { at: 1 }));
+
+        // Lots of patterns still each take one step, and the arena drops in one go.
+        let arena = Arena::new();
+        let patterns: Vec<String> = (0..10_000).map(|i| format!("(key{i}|k{i})=[0-9]+")).collect();
+        let mut matchers: Vec<Matcher> = patterns.iter().map(|text| Matcher::new_in(text, &arena).unwrap()).collect();
+        drop(patterns);
+        assert!(matchers[0].is_match("key0=1"));
+        assert!(matchers[9_999].is_match("k9999=42"));
+        assert!(!matchers[5_000].is_match("key4999=1"));
+        assert_eq!(arena.texts.borrow().len(), 10_000);
+    }
+
+    #[test]
+    fn captures_iter_test() {
+        let mut setting = Matcher::new(r"(?<key>[a-z]+)=(?<value>\d+)").unwrap();
+        let all = setting.captures_iter("width=80, height=24, depth=");
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0]["key"], "width");
+        assert_eq!(all[0]["value"], "80");
+        assert_eq!(all[1]["key"], "height");
+        assert_eq!(all[1]["value"], "24");
+
+        // The captures don't borrow the haystack.
+        let owned = setting.captures_iter(&String::from("size=3"));
+        assert_eq!(owned[0]["value"], "3");
+
+        let mut optional = Matcher::new("(?<a>a)|b").unwrap();
+        assert_eq!(optional.captures_iter("ba").iter().map(|captures| captures.len()).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(optional.captures_iter("xyz").is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        let mut matcher = Matcher::new("abc(d|e|f).[a-z]+").unwrap();
+        assert!(matcher.is_match("abcdxyz"));
+        assert_eq!(matcher.progress.most_tokens_matched, 4);
+
+        let json = serde_json::to_string(&matcher).unwrap();
+        let read_back: Matcher = serde_json::from_str(&json).unwrap();
+        assert_eq!(read_back.progress.most_tokens_matched, 0);
+        assert_eq!(read_back, Matcher::new("abc(d|e|f).[a-z]+").unwrap());
+
+        // Text which JSON escapes, like `\` or `"`, is copied, unless it's one of a
+        // group of words, which have to be borrowed.
+        let escaped = serde_json::to_string(&Matcher::new(r"a\\").unwrap()).unwrap();
+        assert_eq!(serde_json::from_str::<Matcher>(&escaped).unwrap(), Matcher::new(r"a\\").unwrap());
+        let words = serde_json::to_string(&Matcher::new(r#"(a"|b|c|d|e|f|g|h)"#).unwrap()).unwrap();
+        assert!(serde_json::from_str::<Matcher>(&words).is_err());
+
+        let owned: MatcherOwned = r"a\\(b|c)".parse().unwrap();
+        let json = serde_json::to_string(&owned).unwrap();
+        assert_eq!(json, r#""a\\\\(b|c)""#);
+        assert_eq!(serde_json::from_str::<MatcherOwned>(&json).unwrap(), owned);
+        assert!(serde_json::from_str::<MatcherOwned>(r#""a(b""#).is_err());
+    }
+}
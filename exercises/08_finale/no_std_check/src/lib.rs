@@ -0,0 +1,18 @@
+// This crate checks that `ex08` builds without `std`, for use where there's no
+// operating system. Building it on its own turns off the `std` feature, so
+// anything in `ex08` which needs `std` outside of that feature won't compile:
+//
+//     cargo check -p ex08-no-std
+//     cargo check -p ex08-no-std --features serde,unicode-segmentation
+//
+// Building the whole workspace turns `std` back on for `ex08`, since the other
+// crates which use it want it, so that doesn't check anything.
+#![no_std]
+
+use ex08::{Matcher, MatcherParseError};
+
+/// This is whether `pattern` matches the whole of `string`, using only what
+/// `ex08` has without `std`.
+pub fn is_match(pattern: &str, string: &str) -> Result<bool, MatcherParseError> {
+    Ok(Matcher::new(pattern)?.is_match(string))
+}